@@ -3,6 +3,9 @@ CREATE DATABASE isuumo;
 
 DROP TABLE IF EXISTS isuumo.estate;
 DROP TABLE IF EXISTS isuumo.chair;
+DROP TABLE IF EXISTS isuumo.chair_purchase;
+DROP TABLE IF EXISTS isuumo.document_request;
+DROP TABLE IF EXISTS isuumo.document_request_outbox;
 
 CREATE TABLE isuumo.estate
 (
@@ -17,6 +20,7 @@ CREATE TABLE isuumo.estate
     door_height INTEGER             NOT NULL,
     door_width  INTEGER             NOT NULL,
     features    VARCHAR(64)         NOT NULL,
+    features_mask BIGINT UNSIGNED   NULL,
     popularity  INTEGER             NOT NULL,
     location    POINT               NOT NULL,
     INDEX  idx_sort1 (popularity, id),
@@ -42,3 +46,40 @@ CREATE TABLE isuumo.chair
     INDEX  idx_sort1 (popularity, id),
     INDEX  idx\price1 (price)
 );
+
+CREATE TABLE isuumo.chair_purchase
+(
+    id              BIGINT          NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    chair_id        INTEGER         NOT NULL,
+    email           VARCHAR(255)    NOT NULL,
+    idempotency_key VARCHAR(128)    NULL,
+    purchased_at    DATETIME(6)     NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    canceled_at     DATETIME(6)     NULL,
+    UNIQUE KEY uniq_idempotency_key (idempotency_key),
+    INDEX  idx_email (email, id)
+);
+
+CREATE TABLE isuumo.document_request
+(
+    id              BIGINT          NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    estate_id       INTEGER         NOT NULL,
+    email           VARCHAR(255)    NOT NULL,
+    requested_at    DATETIME(6)     NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    INDEX  idx_dedup (estate_id, email, requested_at)
+);
+
+CREATE TABLE isuumo.document_request_outbox
+(
+    id                  BIGINT          NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    document_request_id BIGINT          NOT NULL,
+    estate_id           INTEGER         NOT NULL,
+    email               VARCHAR(255)    NOT NULL,
+    attempts            INTEGER         NOT NULL DEFAULT 0,
+    last_error          VARCHAR(1024)   NULL,
+    claim_token         VARCHAR(64)     NULL,
+    next_attempt_at     DATETIME(6)     NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    delivered_at        DATETIME(6)     NULL,
+    failed_at           DATETIME(6)     NULL,
+    INDEX  idx_pending (delivered_at, failed_at, next_attempt_at),
+    INDEX  idx_claim (claim_token)
+);
@@ -0,0 +1,761 @@
+use crate::features::FeatureSet;
+use crate::fit::{Door, FitEngine, Orientation};
+use crate::pagination::{self, PageStart};
+use crate::sort::{chair_size, ChairSort};
+use crate::{Bounds, Chair, ChairSearchCondition, Range, RangeCondition};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Bound::{self, Included, Unbounded};
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+// Search results are ordered by popularity desc, id desc.
+type SortKey = (Reverse<i64>, Reverse<i64>);
+
+fn sort_key(chair: &Chair) -> SortKey {
+    (Reverse(chair.popularity), Reverse(chair.id))
+}
+
+/// Each list field holds the accepted values of one dimension (range positions for the
+/// numeric ones); a chair matches if it has any of them. Empty lists don't filter.
+/// Explicit bounds apply on top of the selected ranges.
+#[derive(Debug, Default)]
+pub struct ChairQuery<'a> {
+    pub price: Vec<usize>,
+    pub height: Vec<usize>,
+    pub width: Vec<usize>,
+    pub depth: Vec<usize>,
+    pub price_bounds: Bounds,
+    pub height_bounds: Bounds,
+    pub width_bounds: Bounds,
+    pub depth_bounds: Bounds,
+    pub kind: Vec<&'a str>,
+    pub color: Vec<&'a str>,
+    pub features: FeatureSet,
+    pub sort: ChairSort,
+}
+
+impl<'a> ChairQuery<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.price.is_empty()
+            && self.height.is_empty()
+            && self.width.is_empty()
+            && self.depth.is_empty()
+            && self.price_bounds.is_unbounded()
+            && self.height_bounds.is_unbounded()
+            && self.width_bounds.is_unbounded()
+            && self.depth_bounds.is_unbounded()
+            && self.kind.is_empty()
+            && self.color.is_empty()
+            && self.features.is_empty()
+    }
+}
+
+// Chairs bucketed by the ranges of one RangeCondition, each bucket kept in search order.
+#[derive(Debug)]
+struct Buckets {
+    ranges: Vec<Range>,
+    sets: Vec<BTreeSet<SortKey>>,
+}
+
+impl Buckets {
+    fn new(cond: &RangeCondition) -> Self {
+        Self {
+            ranges: cond.ranges.clone(),
+            sets: cond.ranges.iter().map(|_| BTreeSet::new()).collect(),
+        }
+    }
+
+    fn insert(&mut self, value: i64, key: SortKey) {
+        for (range, set) in self.ranges.iter().zip(self.sets.iter_mut()) {
+            if range.contains(value) {
+                set.insert(key);
+            }
+        }
+    }
+
+    fn remove(&mut self, value: i64, key: &SortKey) {
+        for (range, set) in self.ranges.iter().zip(self.sets.iter_mut()) {
+            if range.contains(value) {
+                set.remove(key);
+            }
+        }
+    }
+
+    fn matches(&self, indexes: &[usize], value: i64) -> bool {
+        indexes.is_empty() || indexes.iter().any(|&i| self.ranges[i].contains(value))
+    }
+
+    fn selected(&self, indexes: &[usize]) -> Vec<&BTreeSet<SortKey>> {
+        indexes.iter().map(|&i| &self.sets[i]).collect()
+    }
+}
+
+#[derive(Debug)]
+struct IndexedChair {
+    chair: Chair,
+    features: FeatureSet,
+}
+
+/// In-stock chairs kept in memory so that search never has to touch MySQL.
+///
+/// Chairs leave the index once their stock runs out, so every chair served from it can be
+/// bought and shown by `get_chair_detail`, which rejects sold-out chairs.
+#[derive(Debug)]
+pub struct ChairIndex {
+    chairs: HashMap<i64, IndexedChair>,
+    all: BTreeSet<SortKey>,
+    // (price, id), for the low-priced listing and the price sorts.
+    by_price: BTreeSet<(i64, i64)>,
+    // (size, id) and id, for the size and newest sorts.
+    by_size: BTreeSet<(i64, i64)>,
+    by_id: BTreeSet<i64>,
+    // The conditions the buckets were built from; queries must be resolved against these.
+    condition: Arc<ChairSearchCondition>,
+    price: Buckets,
+    height: Buckets,
+    width: Buckets,
+    depth: Buckets,
+    // Purchases of chairs not in the index, taken off their stock once they are inserted:
+    // a chair can be bought as soon as the commit that restocks it, before it gets here.
+    unindexed_sales: HashMap<i64, i64>,
+    // Chairs changed since `rebuild` took its copy, re-applied when it swaps the copy in.
+    changed: Option<HashSet<i64>>,
+}
+
+impl ChairIndex {
+    pub fn new(cond: Arc<ChairSearchCondition>, chairs: Vec<Chair>) -> Self {
+        let mut index = Self {
+            chairs: HashMap::with_capacity(chairs.len()),
+            all: BTreeSet::new(),
+            by_price: BTreeSet::new(),
+            by_size: BTreeSet::new(),
+            by_id: BTreeSet::new(),
+            price: Buckets::new(&cond.price),
+            height: Buckets::new(&cond.height),
+            width: Buckets::new(&cond.width),
+            depth: Buckets::new(&cond.depth),
+            condition: cond,
+            unindexed_sales: HashMap::new(),
+            changed: None,
+        };
+        for chair in chairs {
+            index.insert(chair);
+        }
+        index
+    }
+
+    pub fn condition(&self) -> &Arc<ChairSearchCondition> {
+        &self.condition
+    }
+
+    /// Re-buckets every chair of `index` for new search conditions.
+    ///
+    /// The new index is built from a copy while `index` keeps serving; chairs that change
+    /// meanwhile are tracked and brought over under the write lock, along with purchases of
+    /// chairs not indexed yet.
+    pub fn rebuild(index: &RwLock<ChairIndex>, cond: Arc<ChairSearchCondition>) {
+        index.write().unwrap().changed = Some(HashSet::new());
+        let chairs = index
+            .read()
+            .unwrap()
+            .chairs
+            .values()
+            .map(|indexed| indexed.chair.clone())
+            .collect();
+        let mut rebuilt = Self::new(cond.clone(), chairs);
+
+        let mut current = index.write().unwrap();
+        let changed = match current.changed.take() {
+            Some(changed) => changed,
+            None => {
+                // Replaced by `initialize` meanwhile; re-bucket what it loaded instead.
+                let chairs = current.chairs.drain().map(|(_, indexed)| indexed.chair).collect();
+                *current = Self::new(cond, chairs);
+                return;
+            }
+        };
+        for id in changed {
+            match current.chairs.get(&id) {
+                Some(indexed) => rebuilt.insert(indexed.chair.clone()),
+                None => {
+                    rebuilt.remove(id);
+                }
+            }
+        }
+        rebuilt.unindexed_sales = mem::take(&mut current.unindexed_sales);
+        *current = rebuilt;
+    }
+
+    /// Adds or replaces a chair. Chairs without stock are not searchable and are dropped.
+    pub fn insert(&mut self, mut chair: Chair) {
+        self.remove(chair.id);
+        chair.stock -= self.unindexed_sales.remove(&chair.id).unwrap_or(0);
+        if chair.stock <= 0 {
+            return;
+        }
+        let key = sort_key(&chair);
+        self.all.insert(key);
+        self.by_price.insert((chair.price, chair.id));
+        self.by_size.insert((chair_size(&chair), chair.id));
+        self.by_id.insert(chair.id);
+        self.price.insert(chair.price, key);
+        self.height.insert(chair.height, key);
+        self.width.insert(chair.width, key);
+        self.depth.insert(chair.depth, key);
+        let features = self.condition.feature.parse_features(&chair.features).unwrap_or_else(|name| {
+            log::warn!("chair {} has unknown feature {}", chair.id, name);
+            self.condition.feature.parse_features_lossy(&chair.features)
+        });
+        self.chairs.insert(chair.id, IndexedChair { chair, features });
+    }
+
+    pub fn remove(&mut self, id: i64) -> Option<Chair> {
+        if let Some(changed) = &mut self.changed {
+            changed.insert(id);
+        }
+        let chair = self.chairs.remove(&id)?.chair;
+        let key = sort_key(&chair);
+        self.all.remove(&key);
+        self.by_price.remove(&(chair.price, chair.id));
+        self.by_size.remove(&(chair_size(&chair), chair.id));
+        self.by_id.remove(&chair.id);
+        self.price.remove(chair.price, &key);
+        self.height.remove(chair.height, &key);
+        self.width.remove(chair.width, &key);
+        self.depth.remove(chair.depth, &key);
+        Some(chair)
+    }
+
+    /// Mirrors `update chair set stock = stock - 1` for a purchased chair.
+    pub fn decrement_stock(&mut self, id: i64) {
+        match self.remove(id) {
+            Some(mut chair) => {
+                chair.stock -= 1;
+                self.insert(chair);
+            }
+            None => *self.unindexed_sales.entry(id).or_insert(0) += 1,
+        }
+    }
+
+    /// Mirrors `update chair set stock = stock + 1` for a canceled purchase. `chair` is only
+    /// used if it is not in the index, that is sold out, and its stock is ignored.
+    pub fn increment_stock(&mut self, chair: Chair) {
+        let mut chair = self.remove(chair.id).unwrap_or(Chair { stock: 0, ..chair });
+        chair.stock += 1;
+        self.insert(chair);
+    }
+
+    pub fn get(&self, id: i64) -> Option<&Chair> {
+        self.chairs.get(&id).map(|indexed| &indexed.chair)
+    }
+
+    /// The `limit` cheapest in-stock chairs, ordered by price asc, id asc.
+    ///
+    /// Sold-out chairs leave the index, so the next cheapest ones move up automatically.
+    pub fn low_priced(&self, limit: usize) -> Vec<Chair> {
+        self.by_price
+            .iter()
+            .take(limit)
+            .map(|(_, id)| self.chairs[id].chair.clone())
+            .collect()
+    }
+
+    /// The `limit` most popular in-stock chairs that go through `door`, with the orientation
+    /// each one has to be carried in.
+    pub fn fitting(
+        &self,
+        engine: &FitEngine,
+        door: Door,
+        limit: usize,
+    ) -> Vec<(Chair, Orientation)> {
+        self.all
+            .iter()
+            .filter_map(|&(_, Reverse(id))| {
+                let chair = &self.chairs[&id].chair;
+                engine.fit(chair, door).map(|orientation| (chair.clone(), orientation))
+            })
+            .take(limit)
+            .collect()
+    }
+
+    fn matches(&self, indexed: &IndexedChair, query: &ChairQuery) -> bool {
+        let chair = &indexed.chair;
+        self.price.matches(&query.price, chair.price)
+            && self.height.matches(&query.height, chair.height)
+            && self.width.matches(&query.width, chair.width)
+            && self.depth.matches(&query.depth, chair.depth)
+            && query.price_bounds.contains(chair.price)
+            && query.height_bounds.contains(chair.height)
+            && query.width_bounds.contains(chair.width)
+            && query.depth_bounds.contains(chair.depth)
+            && (query.kind.is_empty() || query.kind.contains(&chair.kind.as_str()))
+            && (query.color.is_empty() || query.color.contains(&chair.color.as_str()))
+            && indexed.features.contains(query.features)
+    }
+
+    /// Returns the number of matching chairs and up to `limit` of them from `start` on,
+    /// in `query.sort` order.
+    pub fn search(&self, query: &ChairQuery, start: PageStart, limit: usize) -> (i64, Vec<Chair>) {
+        self.scan(query, start, limit, true)
+    }
+
+    /// Like `search` without the count, so the scan stops as soon as the page is full.
+    pub fn page(&self, query: &ChairQuery, start: PageStart, limit: usize) -> Vec<Chair> {
+        self.scan(query, start, limit, false).1
+    }
+
+    fn scan(
+        &self,
+        query: &ChairQuery,
+        start: PageStart,
+        limit: usize,
+        count_all: bool,
+    ) -> (i64, Vec<Chair>) {
+        // Walk the buckets of the most selective dimension; every other condition is checked
+        // per chair. Buckets of one dimension are disjoint, so their union needs no dedup.
+        let selected = vec![
+            self.price.selected(&query.price),
+            self.height.selected(&query.height),
+            self.width.selected(&query.width),
+            self.depth.selected(&query.depth),
+        ]
+        .into_iter()
+        .filter(|sets| !sets.is_empty())
+        .min_by_key(|sets| sets.iter().map(|set| set.len()).sum::<usize>());
+        // A count needs every match, so only a page without one seeks to its cursor.
+        let seek = match start {
+            PageStart::After(key) if !count_all => Some(key),
+            _ => None,
+        };
+        let candidates: Box<dyn Iterator<Item = i64> + '_> = match (selected, query.sort) {
+            (None, sort) => self.sorted_ids(sort, seek),
+            (Some(sets), ChairSort::Popularity) if sets.len() == 1 => Box::new(
+                sets[0]
+                    .range((from(seek.map(popularity_key)), Unbounded))
+                    .map(|&(_, Reverse(id))| id),
+            ),
+            // Buckets are kept in popularity order, so anything else is sorted after filtering.
+            (Some(sets), sort) => {
+                let mut ids: Vec<i64> = sets
+                    .into_iter()
+                    .flatten()
+                    .map(|&(_, Reverse(id))| id)
+                    .filter(|id| self.matches(&self.chairs[id], query))
+                    .collect();
+                ids.sort_unstable_by_key(|id| sort.key(&self.chairs[id].chair));
+                Box::new(ids.into_iter())
+            }
+        };
+
+        let mut count = 0;
+        let mut chairs = Vec::new();
+        for id in candidates {
+            let indexed = &self.chairs[&id];
+            if !self.matches(indexed, query) {
+                continue;
+            }
+            let started = match start {
+                PageStart::Offset(offset) => count >= offset,
+                PageStart::After(key) => query.sort.key(&indexed.chair) > key,
+            };
+            if started && chairs.len() < limit {
+                chairs.push(indexed.chair.clone());
+            } else if started && !count_all {
+                break;
+            }
+            count += 1;
+        }
+        (count as i64, chairs)
+    }
+
+    // Ids of every chair in `sort` order, starting at the `seek` key of that sort if given.
+    fn sorted_ids(
+        &self,
+        sort: ChairSort,
+        seek: Option<pagination::SortKey>,
+    ) -> Box<dyn Iterator<Item = i64> + '_> {
+        // The descending sorts key on negated values, walked backwards from the seek key.
+        let negated = seek.map(|(a, b)| (a.saturating_neg(), b.saturating_neg()));
+        match sort {
+            ChairSort::Popularity => Box::new(
+                self.all
+                    .range((from(seek.map(popularity_key)), Unbounded))
+                    .map(|&(_, Reverse(id))| id),
+            ),
+            ChairSort::PriceAsc => {
+                Box::new(self.by_price.range((from(seek), Unbounded)).map(|&(_, id)| id))
+            }
+            ChairSort::PriceDesc => Box::new(
+                self.by_price
+                    .range((Unbounded, from(negated)))
+                    .rev()
+                    .map(|&(_, id)| id),
+            ),
+            ChairSort::Newest => Box::new(
+                self.by_id
+                    .range((Unbounded, from(negated.map(|(id, _)| id))))
+                    .rev()
+                    .copied(),
+            ),
+            ChairSort::SizeAsc => {
+                Box::new(self.by_size.range((from(seek), Unbounded)).map(|&(_, id)| id))
+            }
+            ChairSort::SizeDesc => Box::new(
+                self.by_size
+                    .range((Unbounded, from(negated)))
+                    .rev()
+                    .map(|&(_, id)| id),
+            ),
+        }
+    }
+}
+
+// Seeking includes the key itself: the scan still skips everything up to the cursor, which
+// keeps it exact for keys, such as those of `newest`, that don't map one-to-one onto a set.
+fn from<T>(seek: Option<T>) -> Bound<T> {
+    seek.map_or(Unbounded, Included)
+}
+
+// The position of a `ChairSort::Popularity` cursor key in the popularity-ordered sets.
+fn popularity_key((popularity, id): pagination::SortKey) -> SortKey {
+    (
+        Reverse(popularity.saturating_neg()),
+        Reverse(id.saturating_neg()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition() -> ChairSearchCondition {
+        let range = r#"{"prefix": "", "suffix": "", "ranges": [{"id": 0, "min": -1, "max": -1}]}"#;
+        let price = r#"{"prefix": "", "suffix": "", "ranges": [
+            {"id": 0, "min": -1, "max": 100},
+            {"id": 1, "min": 100, "max": 200},
+            {"id": 2, "min": 200, "max": -1}]}"#;
+        let list = r#"{"list": []}"#;
+        serde_json::from_str(&format!(
+            r#"{{"width": {0}, "height": {0}, "depth": {0}, "price": {1},
+                "color": {2}, "feature": {2}, "kind": {2}}}"#,
+            range, price, list
+        ))
+        .unwrap()
+    }
+
+    fn chair(id: i64, price: i64, stock: i64) -> Chair {
+        Chair {
+            id,
+            name: format!("chair {}", id),
+            description: String::new(),
+            thumbnail: String::new(),
+            price,
+            height: 100,
+            width: 100,
+            depth: 100,
+            color: String::new(),
+            features: String::new(),
+            kind: String::new(),
+            popularity: 0,
+            stock,
+        }
+    }
+
+    fn low_priced_ids(index: &ChairIndex, limit: usize) -> Vec<i64> {
+        index.low_priced(limit).iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn low_priced_orders_by_price_then_id_and_skips_sold_out() {
+        let index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![chair(1, 300, 1), chair(2, 100, 1), chair(3, 100, 1), chair(4, 50, 0)],
+        );
+        assert_eq!(low_priced_ids(&index, 10), vec![2, 3, 1]);
+        assert_eq!(low_priced_ids(&index, 2), vec![2, 3]);
+    }
+
+    #[test]
+    fn fitting_chairs_are_in_stock_and_by_popularity() {
+        let sized = |id: i64, popularity: i64, width: i64, stock: i64| Chair {
+            popularity,
+            width,
+            ..chair(id, 100, stock)
+        };
+        let mut index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![
+                sized(1, 10, 100, 1),
+                sized(2, 30, 100, 1),
+                sized(3, 20, 300, 1),
+                sized(4, 40, 100, 0),
+                sized(5, 5, 100, 1),
+            ],
+        );
+        let fitting = |index: &ChairIndex, limit| {
+            let door = Door {
+                width: 100,
+                height: 100,
+            };
+            index
+                .fitting(&FitEngine::default(), door, limit)
+                .into_iter()
+                .map(|(chair, orientation)| (chair.id, orientation))
+                .collect::<Vec<_>>()
+        };
+        // Chair 3 fits lying down; chair 4 is sold out.
+        assert_eq!(
+            fitting(&index, 10),
+            vec![
+                (2, Orientation::WidthHeight),
+                (3, Orientation::HeightDepth),
+                (1, Orientation::WidthHeight),
+                (5, Orientation::WidthHeight),
+            ]
+        );
+        index.decrement_stock(2);
+        assert_eq!(
+            fitting(&index, 2),
+            vec![(3, Orientation::HeightDepth), (1, Orientation::WidthHeight)]
+        );
+    }
+
+    #[test]
+    fn selling_out_refills_from_the_next_cheapest() {
+        let mut index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![chair(1, 100, 1), chair(2, 200, 2), chair(3, 300, 1)],
+        );
+        assert_eq!(low_priced_ids(&index, 2), vec![1, 2]);
+
+        index.decrement_stock(1);
+        assert_eq!(low_priced_ids(&index, 2), vec![2, 3]);
+
+        // Chair 2 still has one left after the first purchase.
+        index.decrement_stock(2);
+        assert_eq!(low_priced_ids(&index, 2), vec![2, 3]);
+        index.decrement_stock(2);
+        assert_eq!(low_priced_ids(&index, 2), vec![3]);
+
+        // Buying an already sold-out chair changes nothing.
+        index.decrement_stock(2);
+        assert_eq!(low_priced_ids(&index, 2), vec![3]);
+    }
+
+    #[test]
+    fn cheaper_and_restocked_chairs_enter_the_listing() {
+        let mut index = ChairIndex::new(Arc::new(condition()), vec![chair(1, 100, 1), chair(2, 200, 1)]);
+        index.insert(chair(3, 50, 1));
+        assert_eq!(low_priced_ids(&index, 2), vec![3, 1]);
+
+        index.decrement_stock(3);
+        assert_eq!(low_priced_ids(&index, 2), vec![1, 2]);
+
+        // A canceled purchase puts the chair back, whatever stock it had when read.
+        index.increment_stock(chair(3, 50, 7));
+        assert_eq!(low_priced_ids(&index, 2), vec![3, 1]);
+
+        // Posting a chair without stock does not list it.
+        index.insert(chair(4, 10, 0));
+        assert_eq!(low_priced_ids(&index, 2), vec![3, 1]);
+    }
+
+    #[test]
+    fn purchases_reaching_the_index_before_a_restock_still_count() {
+        let mut index = ChairIndex::new(Arc::new(condition()), vec![chair(1, 100, 1), chair(2, 200, 1)]);
+        index.decrement_stock(1);
+        // The cancellation commits first, then the chair is bought again before the
+        // cancellation updates the index.
+        index.decrement_stock(1);
+        index.increment_stock(chair(1, 100, 0));
+        assert_eq!(low_priced_ids(&index, 2), vec![2]);
+
+        index.increment_stock(chair(1, 100, 0));
+        assert_eq!(low_priced_ids(&index, 2), vec![1, 2]);
+    }
+
+    #[test]
+    fn multi_select_ors_within_and_ands_across_dimensions() {
+        let colored = |id: i64, price: i64, color: &str| Chair {
+            color: color.to_owned(),
+            popularity: id,
+            ..chair(id, price, 1)
+        };
+        let index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![
+                colored(1, 50, "red"),
+                colored(2, 150, "blue"),
+                colored(3, 250, "red"),
+                colored(4, 150, "red"),
+                colored(5, 50, "green"),
+            ],
+        );
+        let ids = |query: &ChairQuery, offset, limit| {
+            let (count, chairs) = index.search(query, PageStart::Offset(offset), limit);
+            (count, chairs.iter().map(|c| c.id).collect::<Vec<_>>())
+        };
+
+        let query = ChairQuery {
+            price: vec![0, 2],
+            ..ChairQuery::default()
+        };
+        assert_eq!(ids(&query, 0, 10), (3, vec![5, 3, 1]));
+
+        let query = ChairQuery {
+            price: vec![0, 1],
+            color: vec!["red", "green"],
+            ..ChairQuery::default()
+        };
+        assert_eq!(ids(&query, 0, 10), (3, vec![5, 4, 1]));
+        // The count covers every match, not just the page.
+        assert_eq!(ids(&query, 1, 1), (3, vec![4]));
+
+        let query = ChairQuery {
+            color: vec!["blue", "green"],
+            ..ChairQuery::default()
+        };
+        assert_eq!(ids(&query, 0, 10), (2, vec![5, 2]));
+    }
+
+    #[test]
+    fn explicit_bounds_narrow_the_selected_ranges() {
+        let index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![chair(1, 50, 1), chair(2, 120, 1), chair(3, 180, 1), chair(4, 250, 1)],
+        );
+        let ids = |query: &ChairQuery| {
+            let (count, chairs) = index.search(query, PageStart::Offset(0), 10);
+            let mut ids: Vec<_> = chairs.iter().map(|c| c.id).collect();
+            ids.sort_unstable();
+            (count, ids)
+        };
+
+        let query = ChairQuery {
+            price_bounds: Bounds {
+                min: Some(100),
+                max: Some(250),
+            },
+            ..ChairQuery::default()
+        };
+        assert!(!query.is_empty());
+        assert_eq!(ids(&query), (2, vec![2, 3]));
+
+        // Range 1 is [100, 200); the explicit max cuts it down to [100, 150).
+        let query = ChairQuery {
+            price: vec![1],
+            price_bounds: Bounds {
+                min: None,
+                max: Some(150),
+            },
+            ..ChairQuery::default()
+        };
+        assert_eq!(ids(&query), (1, vec![2]));
+    }
+
+    #[test]
+    fn sorts_with_and_without_bucket_selection_agree() {
+        let sized = |id: i64, price: i64, popularity: i64, height: i64| Chair {
+            popularity,
+            height,
+            color: "red".to_owned(),
+            ..chair(id, price, 1)
+        };
+        let index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![
+                sized(1, 150, 5, 100),
+                sized(2, 50, 5, 300),
+                sized(3, 150, 9, 200),
+                sized(4, 250, 1, 100),
+            ],
+        );
+        let expected = [
+            (ChairSort::Popularity, vec![3, 2, 1, 4]),
+            (ChairSort::PriceAsc, vec![2, 1, 3, 4]),
+            (ChairSort::PriceDesc, vec![4, 3, 1, 2]),
+            (ChairSort::Newest, vec![4, 3, 2, 1]),
+            (ChairSort::SizeAsc, vec![1, 4, 3, 2]),
+            (ChairSort::SizeDesc, vec![2, 3, 4, 1]),
+        ];
+        for (sort, ids) in expected.iter() {
+            // Color alone walks the sort's own ordering; the price ranges go through buckets.
+            for query in &[
+                ChairQuery {
+                    color: vec!["red"],
+                    sort: *sort,
+                    ..ChairQuery::default()
+                },
+                ChairQuery {
+                    price: vec![0, 1, 2],
+                    sort: *sort,
+                    ..ChairQuery::default()
+                },
+            ] {
+                let (count, chairs) = index.search(query, PageStart::Offset(1), 2);
+                assert_eq!(count, 4);
+                assert_eq!(
+                    chairs.iter().map(|c| c.id).collect::<Vec<_>>(),
+                    ids[1..3].to_vec(),
+                    "{:?}",
+                    sort
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_pages_continue_after_the_last_key() {
+        let priced = |id: i64, price: i64, popularity: i64| Chair {
+            popularity,
+            height: 100 + id % 2,
+            ..chair(id, price, 1)
+        };
+        let index = ChairIndex::new(
+            Arc::new(condition()),
+            vec![
+                priced(1, 150, 3),
+                priced(2, 50, 3),
+                priced(3, 150, 7),
+                priced(4, 250, 3),
+                priced(5, 50, 1),
+                priced(6, 120, 3),
+            ],
+        );
+        let sorts = [
+            ChairSort::Popularity,
+            ChairSort::PriceAsc,
+            ChairSort::PriceDesc,
+            ChairSort::Newest,
+            ChairSort::SizeAsc,
+            ChairSort::SizeDesc,
+        ];
+        // Walking the sort's own set, one bucket, and several buckets.
+        let selections: [&[usize]; 3] = [&[], &[1], &[0, 1, 2]];
+        for &sort in &sorts {
+            for &price in &selections {
+                let query = ChairQuery {
+                    price: price.to_vec(),
+                    sort,
+                    ..ChairQuery::default()
+                };
+                let (total, all) = index.search(&query, PageStart::Offset(0), 10);
+                let ids = |chairs: &[Chair]| chairs.iter().map(|c| c.id).collect::<Vec<_>>();
+                let mut start = PageStart::Offset(0);
+                let mut paged = Vec::new();
+                loop {
+                    let (count, page) = index.search(&query, start, 2);
+                    assert_eq!(count, total);
+                    assert_eq!(ids(&index.page(&query, start, 2)), ids(&page));
+                    match page.last() {
+                        Some(last) => start = PageStart::After(sort.key(last)),
+                        None => break,
+                    }
+                    paged.extend(page);
+                }
+                assert_eq!(ids(&paged), ids(&all), "{:?} {:?}", sort, price);
+            }
+        }
+    }
+}
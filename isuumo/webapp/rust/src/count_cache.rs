@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Distinct conditions kept before the cache starts over.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Result counts per search condition, dropped whenever the searched table changes.
+///
+/// A count computed while an invalidation happens must not outlive it, so `get` hands out
+/// the generation it saw and `insert` discards counts from an older generation.
+#[derive(Debug, Default)]
+pub struct CountCache {
+    inner: Mutex<Generation>,
+}
+
+#[derive(Debug, Default)]
+struct Generation {
+    id: u64,
+    counts: HashMap<String, i64>,
+}
+
+impl CountCache {
+    /// The cached count for `key`, or the generation to pass to `insert` once it is counted.
+    pub fn get(&self, key: &str) -> Result<i64, u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.counts.get(key).copied().ok_or(inner.id)
+    }
+
+    pub fn insert(&self, generation: u64, key: String, count: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.id != generation {
+            return;
+        }
+        if inner.counts.len() >= MAX_ENTRIES {
+            inner.counts.clear();
+        }
+        inner.counts.insert(key, count);
+    }
+
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.id += 1;
+        inner.counts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_until_invalidated() {
+        let cache = CountCache::default();
+        let generation = cache.get("rent < ?|[Int(100)]").unwrap_err();
+        cache.insert(generation, "rent < ?|[Int(100)]".to_owned(), 3);
+        assert_eq!(cache.get("rent < ?|[Int(100)]"), Ok(3));
+        assert!(cache.get("rent < ?|[Int(200)]").is_err());
+
+        cache.invalidate();
+        assert!(cache.get("rent < ?|[Int(100)]").is_err());
+    }
+
+    #[test]
+    fn drops_counts_started_before_an_invalidation() {
+        let cache = CountCache::default();
+        let stale = cache.get("true").unwrap_err();
+        cache.invalidate();
+        cache.insert(stale, "true".to_owned(), 3);
+        assert!(cache.get("true").is_err());
+
+        let current = cache.get("true").unwrap_err();
+        cache.insert(current, "true".to_owned(), 4);
+        assert_eq!(cache.get("true"), Ok(4));
+    }
+}
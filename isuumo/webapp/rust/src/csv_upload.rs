@@ -0,0 +1,472 @@
+use crate::error::{is_duplicate_key, AppError};
+use crate::Pool;
+use actix_multipart::Field;
+use actix_rt::time;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use csv::{ByteRecord, ReaderBuilder};
+use futures::channel::mpsc;
+use futures::{executor, Future, SinkExt, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_BATCH_ROWS: usize = 1000;
+const DEFAULT_IDLE_TIMEOUT_MS: usize = 10_000;
+const DEFAULT_TIMEOUT_MS: usize = 60_000;
+/// MySQL accepts at most this many placeholders in one prepared statement.
+const MAX_PLACEHOLDERS: usize = 65_535;
+/// Invalid rows listed in one error response; parsing stops looking after that.
+pub const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Limits applied to CSV uploads.
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Largest upload accepted, in bytes.
+    pub max_bytes: usize,
+    /// Rows inserted per `INSERT` statement.
+    pub batch_rows: usize,
+    /// Longest wait for the next chunk of an upload before it is aborted.
+    pub idle_timeout: Duration,
+    /// Longest time an upload may take to arrive in full, however steadily it trickles in.
+    pub timeout: Duration,
+}
+
+impl UploadConfig {
+    /// Reads `CSV_UPLOAD_MAX_BYTES`, `CSV_INSERT_BATCH_ROWS`, `CSV_UPLOAD_IDLE_TIMEOUT_MS`
+    /// and `CSV_UPLOAD_TIMEOUT_MS`.
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v: &usize| v > 0)
+                .unwrap_or(default)
+        };
+        Self {
+            max_bytes: var("CSV_UPLOAD_MAX_BYTES", DEFAULT_MAX_BYTES),
+            batch_rows: var("CSV_INSERT_BATCH_ROWS", DEFAULT_BATCH_ROWS),
+            idle_timeout: Duration::from_millis(
+                var("CSV_UPLOAD_IDLE_TIMEOUT_MS", DEFAULT_IDLE_TIMEOUT_MS) as u64,
+            ),
+            timeout: Duration::from_millis(var("CSV_UPLOAD_TIMEOUT_MS", DEFAULT_TIMEOUT_MS) as u64),
+        }
+    }
+}
+
+/// The upload time limit that ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadLimit {
+    Idle(Duration),
+    Total(Duration),
+}
+
+impl fmt::Display for UploadLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadLimit::Idle(idle) => {
+                write!(f, "no upload data received for {} ms", idle.as_millis())
+            }
+            UploadLimit::Total(total) => {
+                write!(f, "upload took longer than {} ms", total.as_millis())
+            }
+        }
+    }
+}
+
+/// A row of an uploaded CSV file.
+pub trait CsvRow: DeserializeOwned {
+    /// Fields of one record.
+    const FIELDS: usize;
+    /// Placeholders one row takes in the multi-row `INSERT`.
+    const PLACEHOLDERS: usize = Self::FIELDS;
+}
+
+/// Why one line of an upload was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineError {
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Parses CSV records of `fields` columns as chunks of input arrive, so only the record
+/// that straddles two chunks is ever buffered.
+pub struct CsvStream {
+    fields: usize,
+    buf: Vec<u8>,
+    // Lines before the start of `buf`.
+    lines: u64,
+}
+
+impl CsvStream {
+    pub fn new(fields: usize) -> Self {
+        Self {
+            fields,
+            buf: Vec::new(),
+            lines: 0,
+        }
+    }
+
+    /// Parses the records completed by `chunk`, with their line numbers.
+    pub fn push<T: DeserializeOwned>(&mut self, chunk: &[u8]) -> Vec<Result<(u64, T), LineError>> {
+        self.buf.extend_from_slice(chunk);
+        self.parse(false)
+    }
+
+    /// Parses whatever is left once the input has ended.
+    pub fn finish<T: DeserializeOwned>(mut self) -> Vec<Result<(u64, T), LineError>> {
+        self.parse(true)
+    }
+
+    fn parse<T: DeserializeOwned>(&mut self, eof: bool) -> Vec<Result<(u64, T), LineError>> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(&self.buf[..]);
+        let mut record = ByteRecord::new();
+        let mut rows = Vec::new();
+        let mut consumed = 0;
+        // Line numbers are counted here: the reader's own don't include quoted newlines.
+        let mut line = self.lines + 1;
+        let mut counted = 0;
+        loop {
+            let result = reader.read_byte_record(&mut record);
+            if let Ok(false) = result {
+                break;
+            }
+            // Records never start with a line break; skipping them finds the actual start.
+            let start = record
+                .position()
+                .map_or(consumed, |p| p.byte() as usize)
+                .max(counted);
+            let start = start
+                + self.buf[start..]
+                    .iter()
+                    .take_while(|&&b| b == b'\r' || b == b'\n')
+                    .count();
+            line += newlines(&self.buf[counted..start]);
+            counted = start;
+            match result {
+                Ok(_) => {
+                    let end = reader.position().byte() as usize;
+                    // A record running up to the end of the buffer may continue in the
+                    // next chunk, so it is parsed again once more input has arrived.
+                    if !eof && end >= self.buf.len() {
+                        break;
+                    }
+                    consumed = end;
+                    rows.push(self.decode(line, &record).map(|row| (line, row)));
+                }
+                Err(e) => {
+                    consumed = self.buf.len();
+                    rows.push(Err(LineError {
+                        line,
+                        reason: e.to_string(),
+                    }));
+                    break;
+                }
+            }
+        }
+        self.lines += newlines(&self.buf[..consumed]);
+        self.buf.drain(..consumed);
+        rows
+    }
+
+    fn decode<T: DeserializeOwned>(&self, line: u64, record: &ByteRecord) -> Result<T, LineError> {
+        if record.len() != self.fields {
+            return Err(LineError {
+                line,
+                reason: format!("expected {} fields, found {}", self.fields, record.len()),
+            });
+        }
+        record.deserialize(None).map_err(|e| LineError {
+            line,
+            reason: match e.kind() {
+                csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                _ => e.to_string(),
+            },
+        })
+    }
+}
+
+fn newlines(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|&&b| b == b'\n').count() as u64
+}
+
+enum Batch<T> {
+    Rows {
+        // Lines of the first and the last row.
+        lines: (u64, u64),
+        rows: Vec<T>,
+    },
+    /// Sent once every row has been streamed and validated.
+    Commit,
+}
+
+/// Inserts the batches sent to the returned sender on a blocking thread, all in one
+/// transaction that is committed on `Batch::Commit` and rolled back if the sender is
+/// dropped before. A batch with an `id` that already exists fails the upload with the
+/// lines of that batch.
+fn spawn_writer<T, F>(
+    param: &'static str,
+    pool: Pool,
+    insert: F,
+) -> (
+    mpsc::Sender<Batch<T>>,
+    impl Future<Output = Result<(), BlockingError<AppError>>>,
+)
+where
+    T: Send + 'static,
+    F: Fn(&mut mysql::Transaction, Vec<T>) -> mysql::Result<()> + Send + 'static,
+{
+    // One batch in flight while the next one is parsed bounds the rows held in memory.
+    let (sender, mut receiver) = mpsc::channel(0);
+    let writer = web::block(move || {
+        let mut conn = pool.get()?;
+        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
+        while let Some(batch) = executor::block_on(receiver.next()) {
+            match batch {
+                Batch::Rows { lines, rows } => insert(&mut tx, rows).map_err(|e| {
+                    if !is_duplicate_key(&e) {
+                        return AppError::from(e);
+                    }
+                    log::info!("{} lines {}-{} duplicate a key : {}", param, lines.0, lines.1, e);
+                    AppError::InvalidCsv {
+                        param,
+                        errors: vec![LineError {
+                            line: lines.0,
+                            reason: format!(
+                                "a row in lines {} to {} already exists: {}",
+                                lines.0,
+                                lines.1,
+                                duplicate_entry(&e)
+                            ),
+                        }],
+                    }
+                })?,
+                Batch::Commit => {
+                    tx.commit()?;
+                    return Ok(());
+                }
+            }
+        }
+        Err(AppError::Internal("upload aborted".to_owned()))
+    });
+    (sender, writer)
+}
+
+// MySQL's own message names the duplicated value and key.
+fn duplicate_entry(e: &mysql::Error) -> &str {
+    match e {
+        mysql::Error::MySqlError(e) => &e.message,
+        _ => "",
+    }
+}
+
+/// Streams the rows of `field` through `accept` and inserts them with `insert` in batches
+/// of one transaction, committed once the whole field has been read.
+///
+/// A database connection and a blocking thread are only taken once the first batch is
+/// full or the input has ended, and are then held until the upload is done; an upload
+/// that sends nothing for `idle_timeout`, or is still arriving after `timeout`, is aborted,
+/// so a stalled or trickling client cannot keep them.
+///
+/// Once a row is rejected nothing more is inserted; the remaining input is still checked so
+/// the error lists every bad line, up to `MAX_REPORTED_ERRORS`.
+pub async fn stream_rows<T, A, I>(
+    param: &'static str,
+    field: &mut Field,
+    config: &UploadConfig,
+    mut accept: A,
+    pool: &Pool,
+    insert: I,
+) -> Result<(), AppError>
+where
+    T: CsvRow + Send + 'static,
+    A: FnMut(&T) -> Result<(), String>,
+    I: Fn(&mut mysql::Transaction, Vec<T>) -> mysql::Result<()> + Clone + Send + 'static,
+{
+    let batch_rows = config.batch_rows.min(MAX_PLACEHOLDERS / T::PLACEHOLDERS).max(1);
+    let mut stream = CsvStream::new(T::FIELDS);
+    let mut batch = Vec::with_capacity(batch_rows);
+    let mut errors = Vec::new();
+    let mut received = 0;
+    let mut finished = false;
+    let mut writer = None;
+    let started = time::Instant::now();
+    let streamed = loop {
+        if finished {
+            break Ok(());
+        }
+        let left = config.timeout.checked_sub(started.elapsed()).unwrap_or_default();
+        let (wait, limit) = if left < config.idle_timeout {
+            (left, UploadLimit::Total(config.timeout))
+        } else {
+            (config.idle_timeout, UploadLimit::Idle(config.idle_timeout))
+        };
+        let chunk = match time::timeout(wait, field.try_next()).await {
+            Ok(chunk) => chunk?,
+            Err(_) => {
+                log::info!("{} upload aborted: {}", param, limit);
+                break Err(AppError::UploadTimeout(limit));
+            }
+        };
+        let rows = match chunk {
+            Some(chunk) => {
+                received += chunk.len();
+                if received > config.max_bytes {
+                    log::info!("{} upload exceeds {} bytes", param, config.max_bytes);
+                    break Err(AppError::UploadTooLarge(config.max_bytes));
+                }
+                stream.push(&chunk)
+            }
+            None => {
+                finished = true;
+                mem::replace(&mut stream, CsvStream::new(T::FIELDS)).finish()
+            }
+        };
+        for row in rows {
+            let checked = row.and_then(|(line, row)| match accept(&row) {
+                Ok(()) => Ok((line, row)),
+                Err(reason) => Err(LineError { line, reason }),
+            });
+            match checked {
+                Ok(row) if errors.is_empty() => batch.push(row),
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.len() >= MAX_REPORTED_ERRORS {
+            errors.truncate(MAX_REPORTED_ERRORS);
+            break Ok(());
+        }
+        if !errors.is_empty() {
+            continue;
+        }
+        while batch.len() >= batch_rows || (finished && !batch.is_empty()) {
+            let rest = batch.split_off(batch_rows.min(batch.len()));
+            let rows: Vec<(u64, T)> = mem::replace(&mut batch, rest);
+            let lines = (rows[0].0, rows[rows.len() - 1].0);
+            let rows = rows.into_iter().map(|(_, row)| row).collect();
+            let (sender, _) = writer
+                .get_or_insert_with(|| spawn_writer(param, pool.clone(), insert.clone()));
+            if sender.send(Batch::Rows { lines, rows }).await.is_err() {
+                // The writer failed; its error is reported below.
+                finished = true;
+                break;
+            }
+        }
+    };
+    let streamed = streamed.and_then(|()| {
+        if errors.is_empty() {
+            return Ok(());
+        }
+        log::info!("{} has {} invalid lines", param, errors.len());
+        Err(AppError::InvalidCsv { param, errors })
+    });
+    let (mut sender, written) = match writer {
+        Some(writer) => writer,
+        // Nothing to insert, so nothing was ever checked out.
+        None => return streamed,
+    };
+    if streamed.is_ok() {
+        // A send error means the writer failed, which it reports.
+        let _ = sender.send(Batch::Commit).await;
+    }
+    // Dropping the sender without a commit rolls the writer back.
+    drop(sender);
+    let written = written.await;
+    streamed?;
+    written.map_err(|e| match AppError::from(e) {
+        e @ AppError::InvalidCsv { .. } => e,
+        e => {
+            log::error!("failed to insert/commit {}: {:?}", param, e);
+            e
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Row {
+        id: i64,
+        name: String,
+    }
+
+    type Parsed = Vec<Result<(u64, Row), LineError>>;
+
+    fn parse_in_chunks(input: &[u8], chunk_len: usize) -> Parsed {
+        let mut stream = CsvStream::new(2);
+        let mut rows: Parsed = Vec::new();
+        for chunk in input.chunks(chunk_len) {
+            rows.extend(stream.push(chunk));
+        }
+        rows.extend(stream.finish());
+        rows
+    }
+
+    fn row(line: u64, id: i64, name: &str) -> Result<(u64, Row), LineError> {
+        Ok((
+            line,
+            Row {
+                id,
+                name: name.to_owned(),
+            },
+        ))
+    }
+
+    #[test]
+    fn chunk_boundaries_do_not_change_the_result() {
+        let input = b"1,a\r\n2,\"multi\nline, quoted\"\n\n3,c";
+        let expected = vec![
+            row(1, 1, "a"),
+            row(2, 2, "multi\nline, quoted"),
+            row(5, 3, "c"),
+        ];
+        for chunk_len in 1..=input.len() {
+            assert_eq!(parse_in_chunks(input, chunk_len), expected, "{}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn reports_bad_lines_and_keeps_going() {
+        let rows = parse_in_chunks(b"1,a\nx,b\n3\n4,d\r\n5,e,f\n", 3);
+        assert_eq!(rows[0], row(1, 1, "a"));
+        assert_eq!(rows[1].as_ref().unwrap_err().line, 2);
+        assert!(rows[1]
+            .as_ref()
+            .unwrap_err()
+            .reason
+            .contains("invalid digit"));
+        assert_eq!(
+            rows[2],
+            Err(LineError {
+                line: 3,
+                reason: "expected 2 fields, found 1".to_owned(),
+            })
+        );
+        assert_eq!(rows[3], row(4, 4, "d"));
+        assert_eq!(rows[4].as_ref().unwrap_err().line, 5);
+        assert_eq!(rows.len(), 5);
+    }
+
+    #[test]
+    fn buffers_at_most_one_partial_record() {
+        let mut stream = CsvStream::new(2);
+        let rows: Parsed = stream.push(b"1,a\n2,b\n3,");
+        assert_eq!(rows, vec![row(1, 1, "a"), row(2, 2, "b")]);
+        assert_eq!(stream.buf, b"3,");
+        // Until more input arrives, a record ending at the end of the buffer is held back.
+        let rows: Parsed = stream.push(b"c\n");
+        assert_eq!(rows, vec![]);
+        assert_eq!(stream.finish::<Row>(), vec![row(3, 3, "c")]);
+    }
+}
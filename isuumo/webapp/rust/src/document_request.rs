@@ -0,0 +1,440 @@
+use crate::Pool;
+use mysql::prelude::*;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const BATCH_SIZE: i64 = 50;
+const MAX_ATTEMPTS: i64 = 10;
+const MAX_BACKOFF_SECS: i64 = 300;
+// A claimed row becomes visible to other workers again if not settled within this time.
+const CLAIM_LEASE_SECS: i64 = 60;
+
+#[derive(Debug, Clone)]
+pub enum SinkEnv {
+    Maildir(PathBuf),
+    Smtp(String),
+}
+
+#[derive(Debug)]
+pub struct DocumentDeliveryEnv {
+    pub dedup_window_secs: i64,
+    poll_interval: Duration,
+    mail_from: String,
+    sink: SinkEnv,
+}
+
+impl DocumentDeliveryEnv {
+    pub fn from_env() -> Self {
+        let sink = match env::var("DOCUMENT_SINK").as_deref() {
+            Ok("smtp") => SinkEnv::Smtp(
+                env::var("DOCUMENT_SMTP_ADDR").unwrap_or_else(|_| "127.0.0.1:1025".to_owned()),
+            ),
+            _ => SinkEnv::Maildir(
+                env::var("DOCUMENT_MAILDIR")
+                    .unwrap_or_else(|_| "/tmp/isuumo/maildir".to_owned())
+                    .into(),
+            ),
+        };
+        Self {
+            dedup_window_secs: env::var("DOCUMENT_REQUEST_DEDUP_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(600),
+            poll_interval: Duration::from_millis(
+                env::var("DOCUMENT_POLL_INTERVAL_MS")
+                    .ok()
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or(1000),
+            ),
+            mail_from: env::var("DOCUMENT_MAIL_FROM")
+                .unwrap_or_else(|_| "noreply@isuumo.example".to_owned()),
+            sink,
+        }
+    }
+}
+
+/// A document request claimed from the outbox for delivery.
+#[derive(Debug)]
+pub struct PendingDocument {
+    pub outbox_id: i64,
+    pub estate_id: i64,
+    pub email: String,
+    pub estate_name: String,
+    pub estate_address: String,
+    attempts: i64,
+}
+
+impl PendingDocument {
+    fn render(&self, from: &str) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: Document request for estate {}\r\n\
+             Message-ID: <document-request-{}@isuumo>\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=UTF-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n\
+             {}\r\n{}\r\n",
+            from, self.email, self.estate_id, self.outbox_id, self.estate_name, self.estate_address,
+        )
+    }
+}
+
+pub trait DocumentSink: Send {
+    fn deliver(&self, document: &PendingDocument) -> io::Result<()>;
+}
+
+/// Drops each document into `dir/new` following the maildir convention.
+pub struct MaildirSink {
+    dir: PathBuf,
+    from: String,
+}
+
+impl MaildirSink {
+    pub fn new(dir: PathBuf, from: String) -> io::Result<Self> {
+        for sub in &["tmp", "new", "cur"] {
+            fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(Self { dir, from })
+    }
+}
+
+impl DocumentSink for MaildirSink {
+    fn deliver(&self, document: &PendingDocument) -> io::Result<()> {
+        let name = format!("{}.{}.isuumo", document.outbox_id, std::process::id());
+        let tmp = self.dir.join("tmp").join(&name);
+        fs::write(&tmp, document.render(&self.from))?;
+        fs::rename(&tmp, self.dir.join("new").join(&name))
+    }
+}
+
+/// Minimal SMTP client speaking to a relay that needs no authentication.
+pub struct SmtpSink {
+    addr: String,
+    from: String,
+    timeout: Duration,
+}
+
+impl SmtpSink {
+    pub fn new(addr: String, from: String) -> Self {
+        Self {
+            addr,
+            from,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+fn read_reply(reader: &mut impl BufRead) -> io::Result<u16> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP connection closed",
+            ));
+        }
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.clone()))?;
+        // "250-..." continues a multi-line reply, "250 ..." ends it.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(code);
+        }
+    }
+}
+
+fn expect_reply(reader: &mut impl BufRead, expected: &[u16]) -> io::Result<()> {
+    let code = read_reply(reader)?;
+    if expected.contains(&code) {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("unexpected SMTP reply {}", code)))
+    }
+}
+
+fn send_command(
+    writer: &mut impl Write,
+    reader: &mut impl BufRead,
+    command: &str,
+    expected: &[u16],
+) -> io::Result<()> {
+    writer.write_all(command.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()?;
+    expect_reply(reader, expected)
+}
+
+// Escapes lines starting with "." so the body cannot end the DATA section early.
+fn dot_stuff(message: &str) -> String {
+    message
+        .split("\r\n")
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+impl DocumentSink for SmtpSink {
+    fn deliver(&self, document: &PendingDocument) -> io::Result<()> {
+        let mut writer = TcpStream::connect(&self.addr)?;
+        writer.set_read_timeout(Some(self.timeout))?;
+        writer.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(writer.try_clone()?);
+
+        expect_reply(&mut reader, &[220])?;
+        send_command(&mut writer, &mut reader, "HELO isuumo", &[250])?;
+        send_command(
+            &mut writer,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", self.from),
+            &[250],
+        )?;
+        send_command(
+            &mut writer,
+            &mut reader,
+            &format!("RCPT TO:<{}>", document.email),
+            &[250, 251],
+        )?;
+        send_command(&mut writer, &mut reader, "DATA", &[354])?;
+        let body = dot_stuff(&document.render(&self.from));
+        writer.write_all(body.trim_end_matches("\r\n").as_bytes())?;
+        send_command(&mut writer, &mut reader, "\r\n.", &[250])?;
+        // The message is accepted at this point; a failed QUIT does not matter.
+        let _ = send_command(&mut writer, &mut reader, "QUIT", &[221]);
+        Ok(())
+    }
+}
+
+fn build_sink(env: &DocumentDeliveryEnv) -> io::Result<Box<dyn DocumentSink>> {
+    let from = env.mail_from.clone();
+    Ok(match &env.sink {
+        SinkEnv::Maildir(dir) => Box::new(MaildirSink::new(dir.clone(), from)?),
+        SinkEnv::Smtp(addr) => Box::new(SmtpSink::new(addr.clone(), from)),
+    })
+}
+
+static CLAIM_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Outbox id, estate id, email, estate name and address (if the estate exists), attempts.
+type ClaimedRow = (i64, i64, String, Option<String>, Option<String>, i64);
+
+/// Claims one batch from the outbox and delivers it. Returns the number of claimed rows.
+fn deliver_batch(pool: &Pool, sink: &dyn DocumentSink) -> Result<usize, Box<dyn Error>> {
+    let mut conn = pool.get()?;
+    let token = format!(
+        "{}-{}",
+        std::process::id(),
+        CLAIM_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    conn.exec_drop(
+        "update document_request_outbox set claim_token = ?, next_attempt_at = now(6) + interval ? second \
+         where delivered_at is null and failed_at is null and next_attempt_at <= now(6) order by id limit ?",
+        (&token, CLAIM_LEASE_SECS, BATCH_SIZE),
+    )?;
+    // Rows whose estate is gone are claimed too and must be settled, or every lease would
+    // claim them again.
+    let claimed: Vec<ClaimedRow> = conn.exec(
+        "select o.id, o.estate_id, o.email, e.name, e.address, o.attempts from document_request_outbox o \
+         left join estate e on e.id = o.estate_id where o.claim_token = ? and o.delivered_at is null",
+        (&token,),
+    )?;
+    let count = claimed.len();
+
+    let mut documents = Vec::with_capacity(count);
+    for (outbox_id, estate_id, email, estate_name, estate_address, attempts) in claimed {
+        match (estate_name, estate_address) {
+            (Some(estate_name), Some(estate_address)) => documents.push(PendingDocument {
+                outbox_id,
+                estate_id,
+                email,
+                estate_name,
+                estate_address,
+                attempts,
+            }),
+            _ => {
+                log::warn!(
+                    "document request {} failed : estate {} no longer exists",
+                    outbox_id,
+                    estate_id
+                );
+                conn.exec_drop(
+                    "update document_request_outbox set attempts = ?, last_error = ?, failed_at = now(6) where id = ?",
+                    (
+                        attempts + 1,
+                        format!("estate {} no longer exists", estate_id),
+                        outbox_id,
+                    ),
+                )?;
+            }
+        }
+    }
+
+    for document in &documents {
+        match sink.deliver(document) {
+            Ok(()) => conn.exec_drop(
+                "update document_request_outbox set delivered_at = now(6), last_error = null where id = ?",
+                (document.outbox_id,),
+            )?,
+            Err(e) => {
+                let attempts = document.attempts + 1;
+                log::warn!(
+                    "document request {} delivery failed ({} attempts) : {}",
+                    document.outbox_id,
+                    attempts,
+                    e
+                );
+                let message: String = e.to_string().chars().take(1024).collect();
+                if attempts >= MAX_ATTEMPTS {
+                    conn.exec_drop(
+                        "update document_request_outbox set attempts = ?, last_error = ?, failed_at = now(6) where id = ?",
+                        (attempts, message, document.outbox_id),
+                    )?;
+                } else {
+                    let backoff = (1i64 << attempts.min(16)).min(MAX_BACKOFF_SECS);
+                    conn.exec_drop(
+                        "update document_request_outbox set attempts = ?, last_error = ?, next_attempt_at = now(6) + interval ? second where id = ?",
+                        (attempts, message, backoff, document.outbox_id),
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Starts the background thread that drains the document request outbox.
+pub fn spawn_worker(
+    pool: Pool,
+    env: Arc<DocumentDeliveryEnv>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let sink = build_sink(&env)?;
+    thread::Builder::new()
+        .name("document-outbox".to_owned())
+        .spawn(move || loop {
+            match deliver_batch(&pool, sink.as_ref()) {
+                Ok(0) => thread::sleep(env.poll_interval),
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("document outbox worker error : {}", e);
+                    thread::sleep(env.poll_interval);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn document() -> PendingDocument {
+        PendingDocument {
+            outbox_id: 7,
+            estate_id: 42,
+            email: "buyer@example.com".to_owned(),
+            estate_name: "ISUCON Heights".to_owned(),
+            estate_address: ".hidden street".to_owned(),
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn dot_stuff_escapes_leading_dots_only() {
+        assert_eq!(dot_stuff("a\r\n.b\r\nc.d"), "a\r\n..b\r\nc.d");
+    }
+
+    #[test]
+    fn smtp_sink_talks_to_fake_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut transcript = Vec::new();
+            writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                transcript.push(line.clone());
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("HELO") {
+                    b"250-fake\r\n250 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            transcript
+        });
+
+        let sink = SmtpSink::new(addr, "noreply@isuumo.example".to_owned());
+        sink.deliver(&document()).unwrap();
+        let transcript = server.join().unwrap();
+
+        assert!(transcript.contains(&"MAIL FROM:<noreply@isuumo.example>\r\n".to_owned()));
+        assert!(transcript.contains(&"RCPT TO:<buyer@example.com>\r\n".to_owned()));
+        assert!(transcript.contains(&"..hidden street\r\n".to_owned()));
+        assert_eq!(transcript.last().unwrap(), "QUIT\r\n");
+    }
+
+    #[test]
+    fn smtp_sink_reports_rejected_recipient() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 fake\r\n").unwrap();
+            for reply in &["250 ok\r\n", "250 ok\r\n", "550 no such user\r\n"] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let sink = SmtpSink::new(addr, "noreply@isuumo.example".to_owned());
+        assert!(sink.deliver(&document()).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn maildir_sink_writes_into_new() {
+        let dir = env::temp_dir().join(format!("isuumo-maildir-test-{}", std::process::id()));
+        let sink = MaildirSink::new(dir.clone(), "noreply@isuumo.example".to_owned()).unwrap();
+        sink.deliver(&document()).unwrap();
+
+        let delivered: Vec<_> = fs::read_dir(dir.join("new")).unwrap().collect();
+        assert_eq!(delivered.len(), 1);
+        let message = fs::read_to_string(delivered[0].as_ref().unwrap().path()).unwrap();
+        assert!(message.contains("To: buyer@example.com\r\n"));
+        assert!(fs::read_dir(dir.join("tmp")).unwrap().next().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use crate::csv_upload::{LineError, UploadLimit};
+use crate::geometry::GeometryError;
+use crate::sql_script::ScriptReport;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Seconds clients are asked to wait before retrying when the database pool is exhausted.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Errors returned by every handler, rendered as a JSON `ErrorResponse`.
+#[derive(Debug)]
+pub enum AppError {
+    InvalidParameter {
+        param: &'static str,
+        message: String,
+    },
+    InvalidRequest(String),
+    InvalidCsv {
+        param: &'static str,
+        errors: Vec<LineError>,
+    },
+    UploadTooLarge(usize),
+    UploadTimeout(UploadLimit),
+    NoSearchCondition,
+    InvalidPolygon(GeometryError),
+    ChairNotFound(i64),
+    ChairSoldOut(i64),
+    EstateNotFound(i64),
+    PurchaseNotFound(i64),
+    IdempotencyKeyReused(String),
+    Database(String),
+    PoolExhausted(String),
+    /// A SQL script failed; `scripts` are the files that loaded before it.
+    Initialize {
+        message: String,
+        scripts: Vec<ScriptReport>,
+    },
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    param: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<LineError>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scripts: Option<Vec<ScriptReport>>,
+}
+
+impl AppError {
+    pub fn invalid_parameter(param: &'static str, message: impl Into<String>) -> Self {
+        AppError::InvalidParameter {
+            param,
+            message: message.into(),
+        }
+    }
+
+    /// Stable identifier clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::InvalidParameter { .. } => "invalid_parameter",
+            AppError::InvalidRequest(_) => "invalid_request",
+            AppError::InvalidCsv { .. } => "invalid_csv",
+            AppError::UploadTooLarge(_) => "upload_too_large",
+            AppError::UploadTimeout(_) => "upload_timeout",
+            AppError::NoSearchCondition => "no_search_condition",
+            AppError::InvalidPolygon(_) => "invalid_polygon",
+            AppError::ChairNotFound(_) => "chair_not_found",
+            AppError::ChairSoldOut(_) => "chair_sold_out",
+            AppError::EstateNotFound(_) => "estate_not_found",
+            AppError::PurchaseNotFound(_) => "purchase_not_found",
+            AppError::IdempotencyKeyReused(_) => "idempotency_key_reused",
+            AppError::Database(_) => "database_error",
+            AppError::PoolExhausted(_) => "service_unavailable",
+            AppError::Initialize { .. } => "initialize_failed",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn param(&self) -> Option<&'static str> {
+        match self {
+            AppError::InvalidParameter { param, .. } | AppError::InvalidCsv { param, .. } => {
+                Some(param)
+            }
+            AppError::InvalidPolygon(_) => Some("coordinates"),
+            AppError::IdempotencyKeyReused(_) => Some("Idempotency-Key"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::InvalidParameter { param, message } => write!(f, "{}: {}", param, message),
+            AppError::InvalidRequest(message) => write!(f, "{}", message),
+            AppError::InvalidCsv { param, errors } => {
+                write!(f, "{}: {} invalid lines", param, errors.len())
+            }
+            AppError::UploadTooLarge(max) => write!(f, "upload is larger than {} bytes", max),
+            AppError::UploadTimeout(limit) => write!(f, "{}", limit),
+            AppError::NoSearchCondition => write!(f, "no search condition given"),
+            AppError::InvalidPolygon(e) => write!(f, "{}", e),
+            AppError::ChairNotFound(id) => write!(f, "chair {} not found", id),
+            AppError::ChairSoldOut(id) => write!(f, "chair {} is sold out", id),
+            AppError::EstateNotFound(id) => write!(f, "estate {} not found", id),
+            AppError::PurchaseNotFound(id) => write!(f, "purchase {} not found", id),
+            AppError::IdempotencyKeyReused(key) => {
+                write!(f, "idempotency key {} was used for a different purchase", key)
+            }
+            AppError::Database(message) => write!(f, "database error: {}", message),
+            AppError::PoolExhausted(message) => {
+                write!(f, "no database connection available: {}", message)
+            }
+            AppError::Initialize { message, .. } => write!(f, "initialize failed: {}", message),
+            AppError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::InvalidParameter { .. }
+            | AppError::InvalidRequest(_)
+            | AppError::InvalidCsv { .. }
+            | AppError::NoSearchCondition
+            | AppError::InvalidPolygon(_) => StatusCode::BAD_REQUEST,
+            AppError::ChairNotFound(_)
+            | AppError::ChairSoldOut(_)
+            | AppError::EstateNotFound(_)
+            | AppError::PurchaseNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::IdempotencyKeyReused(_) => StatusCode::CONFLICT,
+            AppError::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UploadTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            AppError::PoolExhausted(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) | AppError::Initialize { .. } | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Details of server-side failures are logged, not sent to clients.
+        let message = match self {
+            AppError::Database(_) => "database error".to_owned(),
+            AppError::Internal(_) => "internal error".to_owned(),
+            AppError::PoolExhausted(_) => "server is busy, retry later".to_owned(),
+            _ => self.to_string(),
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::PoolExhausted(_) = self {
+            response.header("Retry-After", RETRY_AFTER_SECS.to_string());
+        }
+        response.json(ErrorResponse {
+            code: self.code(),
+            message,
+            param: self.param(),
+            errors: match self {
+                AppError::InvalidCsv { errors, .. } => Some(errors.clone()),
+                _ => None,
+            },
+            scripts: match self {
+                AppError::Initialize { scripts, .. } => Some(scripts.clone()),
+                _ => None,
+            },
+        })
+    }
+}
+
+impl From<BlockingError<AppError>> for AppError {
+    fn from(e: BlockingError<AppError>) -> Self {
+        match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => AppError::Internal("blocking task canceled".to_owned()),
+        }
+    }
+}
+
+impl From<mysql::Error> for AppError {
+    fn from(e: mysql::Error) -> Self {
+        AppError::Database(e.to_string())
+    }
+}
+
+/// Whether `e` is MySQL's `ER_DUP_ENTRY`, a violated primary or unique key.
+pub fn is_duplicate_key(e: &mysql::Error) -> bool {
+    match e {
+        mysql::Error::MySqlError(e) => e.code == 1062,
+        _ => false,
+    }
+}
+
+// r2d2 only fails a checkout once `connection_timeout` has passed without a free connection.
+impl From<r2d2::Error> for AppError {
+    fn from(e: r2d2::Error) -> Self {
+        AppError::PoolExhausted(e.to_string())
+    }
+}
+
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        AppError::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<GeometryError> for AppError {
+    fn from(e: GeometryError) -> Self {
+        AppError::InvalidPolygon(e)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::ListCondition;
+
+/// Upper bound on the number of entries a feature `ListCondition` may declare.
+pub const MAX_FEATURES: usize = 128;
+/// Estate feature sets are stored in a `BIGINT UNSIGNED` column, so fewer fit.
+pub const MAX_STORED_FEATURES: usize = 64;
+
+/// A set of features, one bit per position in the fixture's `ListCondition.list`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet(u128);
+
+impl FeatureSet {
+    fn with(self, position: usize) -> Self {
+        FeatureSet(self.0 | 1 << position)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: FeatureSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The set as stored in `estate.features_mask`; only the first `MAX_STORED_FEATURES`
+    /// positions are kept.
+    pub fn stored(self) -> u64 {
+        self.0 as u64
+    }
+}
+
+impl ListCondition {
+    fn position(&self, name: &str) -> Option<usize> {
+        self.list
+            .iter()
+            .position(|f| f == name)
+            .filter(|&i| i < MAX_FEATURES)
+    }
+
+    /// Parses a comma-separated feature string, failing on the first name not in `list`.
+    pub fn parse_features<'a>(&self, features: &'a str) -> Result<FeatureSet, &'a str> {
+        self.parse_feature_names(features.split(',').filter(|name| !name.is_empty()))
+    }
+
+    /// Like `parse_features`, for names already split, such as a search parameter's.
+    pub fn parse_feature_names<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<FeatureSet, &'a str> {
+        names.into_iter().try_fold(FeatureSet::default(), |set, name| {
+            self.position(name).map(|i| set.with(i)).ok_or(name)
+        })
+    }
+
+    /// A SQL expression computing the stored set of the comma-separated `column`, for rows
+    /// loaded by SQL scripts rather than parsed here.
+    pub fn stored_sql(&self, column: &str, params: &mut Vec<mysql::Value>) -> String {
+        if self.list.is_empty() {
+            return "0".to_owned();
+        }
+        self.list
+            .iter()
+            .take(MAX_STORED_FEATURES)
+            .enumerate()
+            .map(|(i, name)| {
+                params.push(name.as_str().into());
+                format!("(find_in_set(?, {}) > 0) << {}", column, i)
+            })
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    /// Like `parse_features`, but skips unknown names instead of failing.
+    pub fn parse_features_lossy(&self, features: &str) -> FeatureSet {
+        features
+            .split(',')
+            .filter_map(|name| self.position(name))
+            .fold(FeatureSet::default(), FeatureSet::with)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: &[&str]) -> ListCondition {
+        ListCondition {
+            list: items.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_whole_names_into_positions() {
+        let cond = list(&["ペット可", "ペット", "駅近"]);
+        let set = cond.parse_features("駅近,ペット").unwrap();
+        assert_eq!(set.stored(), 0b110);
+        assert!(set.contains(cond.parse_features("ペット").unwrap()));
+        assert!(!set.contains(cond.parse_features("ペット可").unwrap()));
+        assert_eq!(cond.parse_features("駅近,猫"), Err("猫"));
+    }
+
+    #[test]
+    fn stored_sql_sets_one_bit_per_feature() {
+        let mut params = Vec::new();
+        assert_eq!(
+            list(&["a", "b"]).stored_sql("features", &mut params),
+            "(find_in_set(?, features) > 0) << 0 | (find_in_set(?, features) > 0) << 1"
+        );
+        assert_eq!(params, vec![mysql::Value::from("a"), mysql::Value::from("b")]);
+        assert_eq!(list(&[]).stored_sql("features", &mut params), "0");
+    }
+}
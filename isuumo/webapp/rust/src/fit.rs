@@ -0,0 +1,186 @@
+use crate::Chair;
+use serde::Serialize;
+use std::env;
+
+/// How a chair is carried through a door, named by the face it presents to the doorway;
+/// the remaining dimension goes through the door. Either side of the face may be the one
+/// across the door's width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    /// Upright, carried in front or back first.
+    WidthHeight,
+    /// Laid on its front or back.
+    WidthDepth,
+    /// Carried in sideways.
+    HeightDepth,
+}
+
+impl Orientation {
+    /// Orientations in order of preference: upright first, as it is the easiest to carry.
+    pub const ALL: [Orientation; 3] = [
+        Orientation::WidthHeight,
+        Orientation::WidthDepth,
+        Orientation::HeightDepth,
+    ];
+
+    fn face(self, chair: &Chair) -> (i64, i64) {
+        match self {
+            Orientation::WidthHeight => (chair.width, chair.height),
+            Orientation::WidthDepth => (chair.width, chair.depth),
+            Orientation::HeightDepth => (chair.height, chair.depth),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Door {
+    pub width: i64,
+    pub height: i64,
+}
+
+/// Decides whether chairs fit through doors. The door must exceed both sides of the face
+/// the chair presents by at least `clearance`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FitEngine {
+    pub clearance: i64,
+}
+
+impl FitEngine {
+    /// Reads the clearance from `CHAIR_FIT_CLEARANCE`; none by default.
+    pub fn from_env() -> Self {
+        Self {
+            clearance: env::var("CHAIR_FIT_CLEARANCE")
+                .ok()
+                .and_then(|clearance| clearance.parse().ok())
+                .filter(|&clearance: &i64| clearance >= 0)
+                .unwrap_or(0),
+        }
+    }
+
+    /// The preferred orientation in which `chair` goes through `door`, if any.
+    pub fn fit(&self, chair: &Chair, door: Door) -> Option<Orientation> {
+        Orientation::ALL
+            .iter()
+            .copied()
+            .find(|orientation| self.face_fits(orientation.face(chair), door))
+    }
+
+    /// The smallest door opening, as (narrow side, wide side), that lets `chair` through
+    /// in some orientation. A door fits the chair iff it fits this opening either way round,
+    /// which lets the database narrow the candidates before `fit` picks an orientation.
+    pub fn min_opening(&self, chair: &Chair) -> (i64, i64) {
+        let mut dims = [chair.width, chair.height, chair.depth];
+        dims.sort_unstable();
+        (
+            dims[0].saturating_add(self.clearance),
+            dims[1].saturating_add(self.clearance),
+        )
+    }
+
+    fn face_fits(&self, (a, b): (i64, i64), door: Door) -> bool {
+        let (a, b) = (
+            a.saturating_add(self.clearance),
+            b.saturating_add(self.clearance),
+        );
+        (a <= door.width && b <= door.height) || (b <= door.width && a <= door.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chair(width: i64, height: i64, depth: i64) -> Chair {
+        Chair {
+            id: 1,
+            name: String::new(),
+            description: String::new(),
+            thumbnail: String::new(),
+            price: 0,
+            height,
+            width,
+            depth,
+            color: String::new(),
+            features: String::new(),
+            kind: String::new(),
+            popularity: 0,
+            stock: 1,
+        }
+    }
+
+    fn door(width: i64, height: i64) -> Door {
+        Door { width, height }
+    }
+
+    #[test]
+    fn prefers_upright_when_it_fits() {
+        let engine = FitEngine::default();
+        assert_eq!(
+            engine.fit(&chair(60, 100, 60), door(80, 200)),
+            Some(Orientation::WidthHeight)
+        );
+        // Exactly as wide as the door still fits without clearance.
+        assert_eq!(
+            engine.fit(&chair(80, 200, 60), door(80, 200)),
+            Some(Orientation::WidthHeight)
+        );
+    }
+
+    #[test]
+    fn tries_every_orientation() {
+        let engine = FitEngine::default();
+        // Too tall upright, fits laid on its back.
+        assert_eq!(
+            engine.fit(&chair(60, 150, 70), door(80, 100)),
+            Some(Orientation::WidthDepth)
+        );
+        // Too wide and too tall facing the door, fits sideways.
+        assert_eq!(
+            engine.fit(&chair(150, 90, 70), door(80, 100)),
+            Some(Orientation::HeightDepth)
+        );
+        // The face may be turned so its long side runs along the door's height.
+        assert_eq!(
+            engine.fit(&chair(100, 60, 150), door(60, 100)),
+            Some(Orientation::WidthHeight)
+        );
+        // Nothing fits when even the smallest face is too big.
+        assert_eq!(engine.fit(&chair(150, 60, 150), door(100, 60)), None);
+        assert_eq!(engine.fit(&chair(90, 150, 150), door(60, 100)), None);
+    }
+
+    #[test]
+    fn clearance_applies_to_both_sides_of_the_face() {
+        let engine = FitEngine { clearance: 5 };
+        assert_eq!(engine.fit(&chair(80, 100, 90), door(80, 200)), None);
+        assert_eq!(
+            engine.fit(&chair(75, 100, 90), door(80, 200)),
+            Some(Orientation::WidthHeight)
+        );
+        assert_eq!(engine.fit(&chair(75, 196, 196), door(80, 200)), None);
+        assert_eq!(engine.min_opening(&chair(75, 196, 90)), (80, 95));
+    }
+
+    #[test]
+    fn min_opening_agrees_with_fit() {
+        let engine = FitEngine { clearance: 3 };
+        let chairs = vec![chair(60, 150, 70), chair(150, 90, 70), chair(40, 40, 200)];
+        for chair in &chairs {
+            for width in (40..220).step_by(7) {
+                for height in (40..220).step_by(7) {
+                    let (narrow, wide) = engine.min_opening(chair);
+                    let opening_fits =
+                        (narrow <= width && wide <= height) || (wide <= width && narrow <= height);
+                    assert_eq!(
+                        engine.fit(chair, door(width, height)).is_some(),
+                        opening_fits,
+                        "{}x{}",
+                        width,
+                        height
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{BoundingBox, Coordinate, Coordinates};
+use std::fmt;
+
+/// Most points accepted in a polygon; checking it for self-intersection is quadratic.
+pub const MAX_POLYGON_POINTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeometryError {
+    NotFinite,
+    LatitudeOutOfRange(f64),
+    LongitudeOutOfRange(f64),
+    TooFewPoints(usize),
+    TooManyPoints(usize),
+    SelfIntersecting,
+}
+
+impl fmt::Display for GeometryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeometryError::NotFinite => write!(f, "coordinates must be finite numbers"),
+            GeometryError::LatitudeOutOfRange(v) => {
+                write!(f, "latitude {} is outside of [-90, 90]", v)
+            }
+            GeometryError::LongitudeOutOfRange(v) => {
+                write!(f, "longitude {} is outside of [-180, 180]", v)
+            }
+            GeometryError::TooFewPoints(n) => {
+                write!(f, "polygon needs at least 3 distinct points, got {}", n)
+            }
+            GeometryError::TooManyPoints(n) => write!(
+                f,
+                "polygon may have at most {} points, got {}",
+                MAX_POLYGON_POINTS, n
+            ),
+            GeometryError::SelfIntersecting => write!(f, "polygon intersects itself"),
+        }
+    }
+}
+
+impl Coordinate {
+    pub fn validate(&self) -> Result<(), GeometryError> {
+        if !self.latitude.is_finite() || !self.longitude.is_finite() {
+            Err(GeometryError::NotFinite)
+        } else if !(-90.0..=90.0).contains(&self.latitude) {
+            Err(GeometryError::LatitudeOutOfRange(self.latitude))
+        } else if !(-180.0..=180.0).contains(&self.longitude) {
+            Err(GeometryError::LongitudeOutOfRange(self.longitude))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BoundingBox {
+    fn contains(&self, point: &Coordinate) -> bool {
+        self.top_left_corner.latitude <= point.latitude
+            && point.latitude <= self.bottom_right_corner.latitude
+            && self.top_left_corner.longitude <= point.longitude
+            && point.longitude <= self.bottom_right_corner.longitude
+    }
+}
+
+/// A validated simple polygon whose ring is always closed.
+#[derive(Debug)]
+pub struct Polygon {
+    ring: Vec<Coordinate>,
+    bounding_box: BoundingBox,
+}
+
+impl Polygon {
+    pub fn contains(&self, point: &Coordinate) -> bool {
+        self.bounding_box.contains(point) && polygon_contains(&self.ring, point)
+    }
+}
+
+impl Coordinates {
+    /// Validates the client's ring and closes it if needed.
+    pub fn to_polygon(&self) -> Result<Polygon, GeometryError> {
+        if self.coordinates.len() > MAX_POLYGON_POINTS {
+            return Err(GeometryError::TooManyPoints(self.coordinates.len()));
+        }
+        let mut ring: Vec<Coordinate> = Vec::with_capacity(self.coordinates.len() + 1);
+        for c in &self.coordinates {
+            c.validate()?;
+            if ring.last() != Some(c) {
+                ring.push(*c);
+            }
+        }
+        if ring.len() > 1 && ring.first() == ring.last() {
+            ring.pop();
+        }
+        if ring.len() < 3 {
+            return Err(GeometryError::TooFewPoints(ring.len()));
+        }
+        if is_self_intersecting(&ring) {
+            return Err(GeometryError::SelfIntersecting);
+        }
+        ring.push(ring[0]);
+        Ok(Polygon {
+            bounding_box: self.get_bounding_box(),
+            ring,
+        })
+    }
+}
+
+/// Ray-casting test for `point` strictly inside `polygon`.
+///
+/// The ring may be given closed or open; the closing edge from the last vertex back to the
+/// first is always tested, and a repeated first vertex only adds a zero-length edge.
+pub fn polygon_contains(polygon: &[Coordinate], point: &Coordinate) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        if (a.longitude > point.longitude) != (b.longitude > point.longitude) {
+            let latitude = a.latitude
+                + (point.longitude - a.longitude) * (b.latitude - a.latitude)
+                    / (b.longitude - a.longitude);
+            if point.latitude < latitude {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+fn orientation(a: &Coordinate, b: &Coordinate, c: &Coordinate) -> f64 {
+    (b.latitude - a.latitude) * (c.longitude - a.longitude)
+        - (b.longitude - a.longitude) * (c.latitude - a.latitude)
+}
+
+// Whether `c`, known to be collinear with a-b, lies on the segment a-b.
+fn on_segment(a: &Coordinate, b: &Coordinate, c: &Coordinate) -> bool {
+    a.latitude.min(b.latitude) <= c.latitude
+        && c.latitude <= a.latitude.max(b.latitude)
+        && a.longitude.min(b.longitude) <= c.longitude
+        && c.longitude <= a.longitude.max(b.longitude)
+}
+
+fn segments_intersect(p1: &Coordinate, p2: &Coordinate, q1: &Coordinate, q2: &Coordinate) -> bool {
+    let d1 = orientation(q1, q2, p1);
+    let d2 = orientation(q1, q2, p2);
+    let d3 = orientation(p1, p2, q1);
+    let d4 = orientation(p1, p2, q2);
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+    (d1 == 0.0 && on_segment(q1, q2, p1))
+        || (d2 == 0.0 && on_segment(q1, q2, p2))
+        || (d3 == 0.0 && on_segment(p1, p2, q1))
+        || (d4 == 0.0 && on_segment(p1, p2, q2))
+}
+
+// Checks every pair of edges of an open ring for crossings, touches and overlaps.
+fn is_self_intersecting(ring: &[Coordinate]) -> bool {
+    let n = ring.len();
+    let edge = |i: usize| (&ring[i], &ring[(i + 1) % n]);
+    for i in 0..n {
+        for j in i + 1..n {
+            if j == i + 1 || (i == 0 && j == n - 1) {
+                // Adjacent edges share a vertex; they only overlap if they fold back on each other.
+                let (a, b) = edge(i);
+                let (c, d) = edge(j);
+                let (shared, p, q) = if j == i + 1 { (b, a, d) } else { (a, b, c) };
+                if orientation(shared, p, q) == 0.0
+                    && (on_segment(shared, p, q) || on_segment(shared, q, p))
+                {
+                    return true;
+                }
+                continue;
+            }
+            let (a, b) = edge(i);
+            let (c, d) = edge(j);
+            if segments_intersect(a, b, c, d) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(points: &[(f64, f64)]) -> Vec<Coordinate> {
+        points
+            .iter()
+            .map(|&(latitude, longitude)| Coordinate { latitude, longitude })
+            .collect()
+    }
+
+    fn point(latitude: f64, longitude: f64) -> Coordinate {
+        Coordinate { latitude, longitude }
+    }
+
+    fn polygon(points: &[(f64, f64)]) -> Result<Polygon, GeometryError> {
+        Coordinates { coordinates: ring(points) }.to_polygon()
+    }
+
+    #[test]
+    fn square_contains_inner_points_only() {
+        let square = ring(&[(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0)]);
+        assert!(polygon_contains(&square, &point(5.0, 5.0)));
+        assert!(polygon_contains(&square, &point(0.1, 9.9)));
+        assert!(!polygon_contains(&square, &point(-1.0, 5.0)));
+        assert!(!polygon_contains(&square, &point(5.0, 11.0)));
+    }
+
+    #[test]
+    fn concave_polygon_excludes_its_notch() {
+        // A "U" shape opening towards higher latitude between longitudes 4 and 6.
+        let u = ring(&[
+            (0.0, 0.0),
+            (10.0, 0.0),
+            (10.0, 4.0),
+            (2.0, 4.0),
+            (2.0, 6.0),
+            (10.0, 6.0),
+            (10.0, 10.0),
+            (0.0, 10.0),
+        ]);
+        assert!(polygon_contains(&u, &point(5.0, 2.0)));
+        assert!(polygon_contains(&u, &point(5.0, 8.0)));
+        assert!(polygon_contains(&u, &point(1.0, 5.0)));
+        assert!(!polygon_contains(&u, &point(5.0, 5.0)));
+    }
+
+    #[test]
+    fn closed_and_unclosed_rings_agree() {
+        let open = ring(&[(0.0, 0.0), (0.0, 10.0), (10.0, 0.0)]);
+        let closed = ring(&[(0.0, 0.0), (0.0, 10.0), (10.0, 0.0), (0.0, 0.0)]);
+        for p in &[point(2.0, 2.0), point(6.0, 6.0), point(4.9, 4.9), point(-1.0, 1.0)] {
+            assert_eq!(polygon_contains(&open, p), polygon_contains(&closed, p));
+        }
+        assert!(polygon_contains(&open, &point(2.0, 2.0)));
+        assert!(!polygon_contains(&open, &point(6.0, 6.0)));
+    }
+
+    #[test]
+    fn degenerate_rings_contain_nothing() {
+        assert!(!polygon_contains(&[], &point(0.0, 0.0)));
+        assert!(!polygon_contains(&ring(&[(0.0, 0.0), (1.0, 1.0)]), &point(0.5, 0.5)));
+    }
+
+    #[test]
+    fn to_polygon_closes_the_ring() {
+        let open = polygon(&[(0.0, 0.0), (0.0, 10.0), (10.0, 0.0)]).unwrap();
+        let closed = polygon(&[(0.0, 0.0), (0.0, 10.0), (10.0, 0.0), (0.0, 0.0)]).unwrap();
+        assert_eq!(open.ring, closed.ring);
+        assert_eq!(open.ring.first(), open.ring.last());
+        assert!(open.contains(&point(2.0, 2.0)));
+    }
+
+    #[test]
+    fn to_polygon_rejects_invalid_coordinates() {
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (0.0, f64::NAN), (1.0, 0.0)]).unwrap_err(),
+            GeometryError::NotFinite
+        );
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (0.0, f64::INFINITY), (1.0, 0.0)]).unwrap_err(),
+            GeometryError::NotFinite
+        );
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (91.0, 0.0), (1.0, 1.0)]).unwrap_err(),
+            GeometryError::LatitudeOutOfRange(91.0)
+        );
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (0.0, -181.0), (1.0, 1.0)]).unwrap_err(),
+            GeometryError::LongitudeOutOfRange(-181.0)
+        );
+    }
+
+    #[test]
+    fn to_polygon_rejects_degenerate_rings() {
+        assert_eq!(polygon(&[]).unwrap_err(), GeometryError::TooFewPoints(0));
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (1.0, 1.0), (0.0, 0.0)]).unwrap_err(),
+            GeometryError::TooFewPoints(2)
+        );
+        // Collinear points enclose nothing; the ring folds back over itself.
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)]).unwrap_err(),
+            GeometryError::SelfIntersecting
+        );
+    }
+
+    #[test]
+    fn to_polygon_rejects_too_many_points() {
+        // A convex ring around the origin, so only the point count can fail it.
+        let circle = |n: usize| {
+            let points: Vec<(f64, f64)> = (0..n)
+                .map(|i| {
+                    let angle = i as f64 * 2.0 * std::f64::consts::PI / n as f64;
+                    (angle.sin(), angle.cos())
+                })
+                .collect();
+            polygon(&points)
+        };
+        assert!(circle(MAX_POLYGON_POINTS).is_ok());
+        assert_eq!(
+            circle(MAX_POLYGON_POINTS + 1).unwrap_err(),
+            GeometryError::TooManyPoints(MAX_POLYGON_POINTS + 1)
+        );
+    }
+
+    #[test]
+    fn to_polygon_rejects_self_intersection() {
+        // Bow tie.
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (10.0, 10.0), (10.0, 0.0), (0.0, 10.0)]).unwrap_err(),
+            GeometryError::SelfIntersecting
+        );
+        // A vertex touching a non-adjacent edge.
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (5.0, 0.0), (0.0, 10.0)]).unwrap_err(),
+            GeometryError::SelfIntersecting
+        );
+        // An edge folding back over its predecessor.
+        assert_eq!(
+            polygon(&[(0.0, 0.0), (10.0, 0.0), (5.0, 0.0), (5.0, 5.0)]).unwrap_err(),
+            GeometryError::SelfIntersecting
+        );
+    }
+}
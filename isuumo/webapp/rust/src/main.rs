@@ -1,26 +1,51 @@
 use actix_multipart::Multipart;
-use actix_web::{middleware, web, App, Error as AWError, HttpResponse, HttpServer};
-use bytes::BytesMut;
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use futures::TryStreamExt;
 use listenfd::ListenFd;
 use mysql::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::env;
-use std::fs::File;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::Mutex;
 
+use chair_index::{ChairIndex, ChairQuery};
+use csv_upload::{CsvRow, UploadConfig};
+use count_cache::CountCache;
+use document_request::DocumentDeliveryEnv;
+use error::AppError;
+use fit::{Door, FitEngine, Orientation};
+use nazotte::EstateLocationIndex;
+use pagination::{PageStart, Pagination, PaginationConfig};
+use search_condition::SearchConditionStore;
+use snapshot::SnapshotCell;
+use sort::{ChairSort, EstateSort};
+use sql_script::ScriptReport;
 
 #[macro_use]
 mod newrelic_util;
+mod chair_index;
+mod csv_upload;
+mod count_cache;
+mod document_request;
+mod error;
+mod features;
+mod fit;
+mod geometry;
+mod nazotte;
+mod pagination;
+mod rendered_json;
+mod search_condition;
+mod snapshot;
+mod sort;
+mod sql_script;
 
 type Pool = r2d2::Pool<r2d2_mysql::MysqlConnectionManager>;
-type BlockingDBError = actix_web::error::BlockingError<mysql::Error>;
+type BlockingDBError = actix_web::error::BlockingError<AppError>;
 
 const LIMIT: i64 = 20;
 const NAZOTTE_LIMIT: usize = 50;
+const DEFAULT_POOL_MAX_SIZE: u32 = 10;
+const DEFAULT_POOL_TIMEOUT_MS: u64 = 5000;
 
 #[derive(Debug)]
 struct MySQLConnectionEnv {
@@ -29,6 +54,10 @@ struct MySQLConnectionEnv {
     user: String,
     db_name: String,
     password: String,
+    pool_max_size: u32,
+    pool_timeout: std::time::Duration,
+    // SQL files under `mysql/db` that `/initialize` loads into this shard after the schema.
+    init_scripts: &'static [&'static str],
 }
 
 #[derive(Debug)]
@@ -50,6 +79,17 @@ impl MySQLConnectionEnv {
             user: env::var("CHAIR_MYSQL_USER").unwrap_or_else(|_| "isucon".to_owned()),
             db_name: env::var("CHAIR_MYSQL_DBNAME").unwrap_or_else(|_| "isuumo".to_owned()),
             password: env::var("CHAIR_MYSQL_PASS").unwrap_or_else(|_| "isucon".to_owned()),
+            pool_max_size: env::var("CHAIR_MYSQL_POOL_MAX_SIZE")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(DEFAULT_POOL_MAX_SIZE),
+            pool_timeout: std::time::Duration::from_millis(
+                env::var("CHAIR_MYSQL_POOL_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or(DEFAULT_POOL_TIMEOUT_MS),
+            ),
+            init_scripts: &["2_DummyChairData.sql"],
         }
     }
 
@@ -65,6 +105,56 @@ impl MySQLConnectionEnv {
             user: env::var("ESTATE_MYSQL_USER").unwrap_or_else(|_| "isucon".to_owned()),
             db_name: env::var("ESTATE_MYSQL_DBNAME").unwrap_or_else(|_| "isuumo".to_owned()),
             password: env::var("ESTATE_MYSQL_PASS").unwrap_or_else(|_| "isucon".to_owned()),
+            pool_max_size: env::var("ESTATE_MYSQL_POOL_MAX_SIZE")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(DEFAULT_POOL_MAX_SIZE),
+            pool_timeout: std::time::Duration::from_millis(
+                env::var("ESTATE_MYSQL_POOL_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or(DEFAULT_POOL_TIMEOUT_MS),
+            ),
+            init_scripts: &["1_DummyEstateData.sql"],
+        }
+    }
+}
+
+impl MySQLConnectionEnv {
+    fn same_database(&self, other: &MySQLConnectionEnv) -> bool {
+        self.host == other.host && self.port == other.port && self.db_name == other.db_name
+    }
+}
+
+/// The schema drops and recreates the whole database, not just one shard's tables.
+const SCHEMA_SCRIPT: &str = "0_Schema.sql";
+
+/// The scripts `/initialize` runs on each shard.
+#[derive(Debug, PartialEq)]
+struct InitPlan {
+    chair: Vec<&'static str>,
+    estate: Vec<&'static str>,
+    /// Set when both shards are one database: the schema then runs only once, with the
+    /// estate scripts, and the chair scripts must wait for them to finish.
+    serial: bool,
+}
+
+impl MultiMySQLConnectionEnv {
+    fn init_plan(&self) -> InitPlan {
+        let with_schema = |env: &MySQLConnectionEnv| {
+            std::iter::once(SCHEMA_SCRIPT)
+                .chain(env.init_scripts.iter().copied())
+                .collect()
+        };
+        let serial = self.chair.same_database(&self.estate);
+        InitPlan {
+            chair: if serial {
+                self.chair.init_scripts.to_vec()
+            } else {
+                with_schema(&self.chair)
+            },
+            estate: with_schema(&self.estate),
+            serial,
         }
     }
 }
@@ -79,7 +169,11 @@ impl Default for MultiMySQLConnectionEnv {
 }
 
 struct AppCache {
-    low_priced_estates: Mutex<Vec<Estate>>,
+    low_priced_estates: SnapshotCell<EstateListResponse>,
+    chair_index: RwLock<ChairIndex>,
+    estate_locations: RwLock<EstateLocationIndex>,
+    // Estate search counts; chair counts come from the index in the same pass as the page.
+    estate_counts: CountCache,
 }
 
 #[derive(Clone)]
@@ -88,6 +182,22 @@ struct MultiPool {
     estate: Pool,
 }
 
+// Every path parameter in the routes below is an `{id}`.
+fn path_config() -> web::PathConfig {
+    web::PathConfig::default()
+        .error_handler(|e, _| AppError::invalid_parameter("id", e.to_string()).into())
+}
+
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|e, _| AppError::InvalidRequest(e.to_string()).into())
+}
+
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|e, _| AppError::InvalidRequest(e.to_string()).into())
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     if env::var("RUST_LOG").is_err() {
@@ -96,14 +206,12 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let mysql_connection_env = Arc::new(MultiMySQLConnectionEnv::default());
-    let chair_search_condition: Arc<ChairSearchCondition> = {
-        let file = File::open("../fixture/chair_condition.json")?;
-        Arc::new(serde_json::from_reader(file)?)
-    };
-    let estate_search_condition: Arc<EstateSearchCondition> = {
-        let file = File::open("../fixture/estate_condition.json")?;
-        Arc::new(serde_json::from_reader(file)?)
-    };
+    let search_conditions = web::Data::new(SearchConditionStore::from_env().map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid search condition: {}", e),
+        )
+    })?);
 
     let manager_chair = r2d2_mysql::MysqlConnectionManager::new(
         mysql::OptsBuilder::new()
@@ -114,8 +222,8 @@ async fn main() -> std::io::Result<()> {
             .pass(Some(&mysql_connection_env.chair.password)),
     );
     let pool_chair = r2d2::Pool::builder()
-        .max_size(10)
-        .connection_timeout(std::time::Duration::from_secs(300))
+        .max_size(mysql_connection_env.chair.pool_max_size)
+        .connection_timeout(mysql_connection_env.chair.pool_timeout)
         .build(manager_chair)
         .expect("Failed to create connection pool for chair");
 
@@ -128,8 +236,8 @@ async fn main() -> std::io::Result<()> {
             .pass(Some(&mysql_connection_env.estate.password)),
     );
     let pool_estate = r2d2::Pool::builder()
-        .max_size(10)
-        .connection_timeout(std::time::Duration::from_secs(300))
+        .max_size(mysql_connection_env.estate.pool_max_size)
+        .connection_timeout(mysql_connection_env.estate.pool_timeout)
         .build(manager_estate)
         .expect("Failed to create connection pool for estate");
 
@@ -138,9 +246,33 @@ async fn main() -> std::io::Result<()> {
         .exec("select * from estate order by rent asc, id asc limit ?",
               (LIMIT,),)
         .expect("Failed to fetch lower price estates at app start");
-    
+
+    let initial_chairs = pool_chair.get()
+        .expect("Failed to checkout database connection")
+        .query("select * from chair where stock > 0")
+        .expect("Failed to fetch chairs at app start");
+
+    fill_estate_feature_masks(
+        &mut *pool_estate.get().expect("Failed to checkout database connection"),
+        &search_conditions.load().estate.feature,
+    )
+    .expect("Failed to fill estate feature masks at app start");
+
+    let initial_locations = pool_estate.get()
+        .expect("Failed to checkout database connection")
+        .query("select id, latitude, longitude, popularity from estate")
+        .expect("Failed to fetch estate locations at app start");
+
     let app_cache = web::Data::new(AppCache {
-        low_priced_estates: Mutex::new(initial_estates),
+        low_priced_estates: SnapshotCell::new(EstateListResponse {
+            estates: initial_estates,
+        }),
+        chair_index: RwLock::new(ChairIndex::new(
+            search_conditions.load().chair.clone(),
+            initial_chairs,
+        )),
+        estate_locations: RwLock::new(EstateLocationIndex::new(initial_locations)),
+        estate_counts: CountCache::default(),
     });
     
     let pool = MultiPool{
@@ -148,6 +280,14 @@ async fn main() -> std::io::Result<()> {
         estate: pool_estate,
     };
 
+    let document_env = Arc::new(DocumentDeliveryEnv::from_env());
+    let fit_engine = FitEngine::from_env();
+    let upload_config = UploadConfig::from_env();
+    let pagination_config = web::Data::new(PaginationConfig::from_env());
+    document_request::spawn_worker(pool.estate.clone(), document_env.clone())
+        .expect("Failed to start document request worker");
+    search_condition::spawn_reloader(search_conditions.clone(), app_cache.clone())?;
+
     newrelic_init!();
 
     let mut listenfd = ListenFd::from_env();
@@ -155,11 +295,18 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .data(pool.clone())
             .data(mysql_connection_env.clone())
-            .data(chair_search_condition.clone())
-            .data(estate_search_condition.clone())
+            .data(document_env.clone())
+            .data(fit_engine)
+            .data(upload_config.clone())
             .app_data(app_cache.clone())
+            .app_data(search_conditions.clone())
+            .app_data(pagination_config.clone())
+            .app_data(path_config())
+            .app_data(query_config())
+            .app_data(json_config())
             .wrap(middleware::Logger::default())
             .route("/initialize", web::post().to(initialize))
+            .route("/pool_stats", web::get().to(get_pool_stats))
             .service(
                 web::scope("/api")
                     .service(
@@ -171,6 +318,11 @@ async fn main() -> std::io::Result<()> {
                                 web::get().to(get_chair_search_condition),
                             )
                             .route("/buy/{id}", web::post().to(buy_chair))
+                            .route("/purchases", web::get().to(get_chair_purchases))
+                            .route(
+                                "/purchases/{id}/cancel",
+                                web::post().to(cancel_chair_purchase),
+                            )
                             .route("/{id}", web::get().to(get_chair_detail))
                             .route("", web::post().to(post_chair)),
                     )
@@ -193,6 +345,10 @@ async fn main() -> std::io::Result<()> {
                     .route(
                         "/recommended_estate/{id}",
                         web::get().to(search_recommended_estate_with_chair),
+                    )
+                    .route(
+                        "/recommended_chair/{id}",
+                        web::get().to(search_recommended_chair_with_estate),
                     ),
             )
     });
@@ -227,14 +383,80 @@ struct RangeCondition {
     ranges: Vec<Range>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A half-open range `[min, max)`; a missing bound leaves that side open.
+#[derive(Debug, Deserialize, Serialize, Clone)]
 struct Range {
     id: i64,
-    min: i64,
-    max: i64,
+    #[serde(with = "open_bound")]
+    min: Option<i64>,
+    #[serde(with = "open_bound")]
+    max: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Range {
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    fn contains(&self, value: i64) -> bool {
+        self.bounds().contains(value)
+    }
+}
+
+/// Half-open bounds `[min, max)` on a numeric column, from a fixture range or from
+/// explicit `...Min`/`...Max` search parameters.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Bounds {
+    min: Option<i64>,
+    max: Option<i64>,
+}
+
+impl Bounds {
+    fn contains(&self, value: i64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value < max)
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    /// Renders the bounds as `column >= ? and column < ?`, or `None` if unbounded.
+    fn to_sql(self, column: &str, params: &mut Vec<mysql::Value>) -> Option<String> {
+        let mut sql = Vec::with_capacity(2);
+        if let Some(min) = self.min {
+            sql.push(format!("{} >= ?", column));
+            params.push(min.into());
+        }
+        if let Some(max) = self.max {
+            sql.push(format!("{} < ?", column));
+            params.push(max.into());
+        }
+        if sql.is_empty() {
+            None
+        } else {
+            Some(sql.join(" and "))
+        }
+    }
+}
+
+// The fixtures and the search condition API write an open bound as `-1`.
+mod open_bound {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bound: &Option<i64>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(bound.unwrap_or(-1))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+        let value = i64::deserialize(deserializer)?;
+        Ok(if value == -1 { None } else { Some(value) })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 struct ListCondition {
     list: Vec<String>,
 }
@@ -249,74 +471,176 @@ struct EstateSearchCondition {
     feature: ListCondition,
 }
 
+#[derive(Debug, Serialize)]
+struct PoolStats {
+    #[serde(rename = "maxSize")]
+    max_size: u32,
+    connections: u32,
+    #[serde(rename = "idleConnections")]
+    idle_connections: u32,
+    #[serde(rename = "inUse")]
+    in_use: u32,
+}
+
+impl PoolStats {
+    fn new(pool: &Pool) -> Self {
+        let state = pool.state();
+        Self {
+            max_size: pool.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PoolStatsResponse {
+    chair: PoolStats,
+    estate: PoolStats,
+}
+
+async fn get_pool_stats(db: web::Data<MultiPool>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(PoolStatsResponse {
+        chair: PoolStats::new(&db.chair),
+        estate: PoolStats::new(&db.estate),
+    }))
+}
+
 #[derive(Debug, Serialize)]
 struct InitializeResponse {
     language: String,
+    chair: ShardInitializeReport,
+    estate: ShardInitializeReport,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShardInitializeReport {
+    scripts: Vec<ScriptReport>,
+    scripts_elapsed_ms: u64,
+    caches_elapsed_ms: u64,
+    elapsed_ms: u64,
+}
+
+/// Loads the shard's SQL files, then rebuilds the caches fed from it with `rebuild_caches`.
+fn initialize_shard(
+    pool: &Pool,
+    env: &MySQLConnectionEnv,
+    scripts: &[&str],
+    sql_dir: &std::path::Path,
+    rebuild_caches: impl FnOnce(&mut mysql::Conn) -> Result<(), AppError>,
+) -> Result<ShardInitializeReport, AppError> {
+    let started = std::time::Instant::now();
+    let mut conn = pool.get()?;
+    let mut reports = Vec::with_capacity(scripts.len());
+    let mut failed = None;
+    for file in scripts {
+        match sql_script::run_script(&mut *conn, &sql_dir.join(file)) {
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                failed = Some(e);
+                break;
+            }
+        }
+    }
+    // The schema drops and recreates the database, leaving this connection without a default one.
+    let reselect = conn.query_drop(format!("use `{}`", env.db_name.replace('`', "``")));
+    if let Some(e) = failed {
+        log::error!("Initialize script failed : {}", e);
+        return Err(AppError::Initialize {
+            message: e.to_string(),
+            scripts: reports,
+        });
+    }
+    reselect?;
+    let scripts_elapsed = started.elapsed();
+
+    rebuild_caches(&mut conn)?;
+    let elapsed = started.elapsed();
+    Ok(ShardInitializeReport {
+        scripts: reports,
+        scripts_elapsed_ms: scripts_elapsed.as_millis() as u64,
+        caches_elapsed_ms: (elapsed - scripts_elapsed).as_millis() as u64,
+        elapsed_ms: elapsed.as_millis() as u64,
+    })
 }
 
 async fn initialize(
     db: web::Data<MultiPool>,
     data: web::Data<AppCache>,
     mysql_connection_env: web::Data<Arc<MultiMySQLConnectionEnv>>,
-) -> Result<HttpResponse, AWError> {
+    search_conditions: web::Data<SearchConditionStore>,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("POST /initialize");
 
     let sql_dir = std::path::Path::new("..").join("mysql").join("db");
-    let paths = [
-        sql_dir.join("0_Schema.sql"),
-        sql_dir.join("1_DummyEstateData.sql"),
-        sql_dir.join("2_DummyChairData.sql"),
-    ];
-    for env in &[&mysql_connection_env.chair, &mysql_connection_env.estate] {
-        for p in paths.iter() {
-            let sql_file = p.canonicalize().unwrap();
-            let cmd_str = format!(
-                "mysql -h {} -P {} -u {} -p{} {} < {}",
-                env.host,
-                env.port,
-                env.user,
-                env.password,
-                env.db_name,
-                sql_file.display()
-            );
-            let status = tokio::process::Command::new("bash")
-                .arg("-c")
-                .arg(cmd_str)
-                .status()
-                .await
-                .map_err(|e| {
-                    log::error!("Initialize script {} failed : {:?}", p.display(), e);
-                    HttpResponse::InternalServerError()
-                })?;
-            if !status.success() {
-                log::error!("Initialize script {} failed", p.display());
-                return Ok(HttpResponse::InternalServerError().finish());
-            }
+
+    let plan = Arc::new(mysql_connection_env.init_plan());
+
+    let chair = {
+        let db = db.clone();
+        let env = mysql_connection_env.clone();
+        let data = data.clone();
+        let plan = plan.clone();
+        let sql_dir = sql_dir.clone();
+        move || {
+            web::block(move || {
+                initialize_shard(&db.chair, &env.chair, &plan.chair, &sql_dir, |conn| {
+                    let chairs = conn.query("select * from chair where stock > 0")?;
+                    // Built outside the lock so searches keep being served meanwhile.
+                    let condition = data.chair_index.read().unwrap().condition().clone();
+                    let index = ChairIndex::new(condition, chairs);
+                    *data.chair_index.write().unwrap() = index;
+                    Ok(())
+                })
+            })
         }
-    }
-    {
-        // initialize low_priced_estates
-        let estates = web::block(move || {
-            let mut conn = db.estate.get().expect("Failed to checkout database connection");
-            conn.exec(
-                "select * from estate order by rent asc, id asc limit ?",
-                (LIMIT,),
-            )
-        })
-        .await
-        .map_err(|e| {
-            log::error!("get_low_priced_estate DB execution error : {:?}", e);
-            HttpResponse::InternalServerError()
-        })?;
-        let mut cache = data.low_priced_estates.lock().unwrap();
-        *cache = estates;
-    }
+    };
+    let estate = {
+        let env = mysql_connection_env.clone();
+        let plan = plan.clone();
+        let condition = search_conditions.load().estate.clone();
+        move || {
+            web::block(move || {
+                initialize_shard(&db.estate, &env.estate, &plan.estate, &sql_dir, |conn| {
+                    fill_estate_feature_masks(conn, &condition.feature)?;
+                    let locations =
+                        conn.query("select id, latitude, longitude, popularity from estate")?;
+                    let estates = conn.exec(
+                        "select * from estate order by rent asc, id asc limit ?",
+                        (LIMIT,),
+                    )?;
+                    *data.estate_locations.write().unwrap() = EstateLocationIndex::new(locations);
+                    data.estate_counts.invalidate();
+                    data.low_priced_estates.store(EstateListResponse { estates });
+                    Ok(())
+                })
+            })
+        }
+    };
+    // Shards on different databases are loaded concurrently.
+    let loaded = if plan.serial {
+        match estate().await {
+            Ok(estate) => chair().await.map(|chair| (chair, estate)),
+            Err(e) => Err(e),
+        }
+    } else {
+        futures::future::try_join(chair(), estate()).await
+    };
+    let (chair, estate) = loaded.map_err(|e: BlockingDBError| {
+        log::error!("initialize failed : {:?}", e);
+        AppError::from(e)
+    })?;
+
     Ok(HttpResponse::Ok().json(InitializeResponse {
         language: "rust".to_owned(),
+        chair,
+        estate,
     }))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Chair {
     id: i64,
     name: String,
@@ -361,35 +685,35 @@ impl FromRow for Chair {
 async fn get_chair_detail(
     db: web::Data<MultiPool>,
     path: web::Path<(i64,)>,
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/chair/{id}");
 
     let id = path.0;
 
     let chair: Option<Chair> = web::block(move || {
-        let mut conn = db.chair.get().expect("Failed to checkout database connection");
-        conn.exec_first("select * from chair where id = ?", (id,))
+        let mut conn = db.chair.get()?;
+        Ok(conn.exec_first("select * from chair where id = ?", (id,))?)
     })
     .await
-    .map_err(|e| {
+    .map_err(|e: BlockingDBError| {
         log::error!("Failed to get the chair from id : {}", e);
-        HttpResponse::InternalServerError()
+        AppError::from(e)
     })?;
 
     if let Some(chair) = chair {
         if chair.stock <= 0 {
             log::info!("requested id's chair is sold out : {}", id);
-            Ok(HttpResponse::NotFound().finish())
+            Err(AppError::ChairSoldOut(id))
         } else {
             Ok(HttpResponse::Ok().json(chair))
         }
     } else {
         log::info!("requested id's chair not found : {}", id);
-        Ok(HttpResponse::NotFound().finish())
+        Err(AppError::ChairNotFound(id))
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct CSVChair {
     id: i64,
     name: String,
@@ -406,87 +730,99 @@ struct CSVChair {
     stock: i64,
 }
 
-impl Into<Chair> for CSVChair {
-    fn into(self) -> Chair {
+impl CsvRow for CSVChair {
+    const FIELDS: usize = 13;
+}
+
+impl From<CSVChair> for Chair {
+    fn from(csv: CSVChair) -> Self {
         Chair {
-            id: self.id,
-            name: self.name,
-            description: self.description,
-            thumbnail: self.thumbnail,
-            price: self.price,
-            height: self.height,
-            width: self.width,
-            depth: self.depth,
-            color: self.color,
-            features: self.features,
-            kind: self.kind,
-            popularity: self.popularity,
-            stock: self.stock,
+            id: csv.id,
+            name: csv.name,
+            description: csv.description,
+            thumbnail: csv.thumbnail,
+            price: csv.price,
+            height: csv.height,
+            width: csv.width,
+            depth: csv.depth,
+            color: csv.color,
+            features: csv.features,
+            kind: csv.kind,
+            popularity: csv.popularity,
+            stock: csv.stock,
         }
     }
 }
 
-async fn post_chair(db: web::Data<MultiPool>, mut payload: Multipart) -> Result<HttpResponse, AWError> {
+async fn post_chair(
+    db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
+    search_conditions: web::Data<SearchConditionStore>,
+    upload_config: web::Data<UploadConfig>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("POST /api/chair");
 
-    let mut chairs: Option<Vec<Chair>> = None;
-    while let Ok(Some(field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition().unwrap();
-        let name = content_disposition.get_name().unwrap();
-        if name == "chairs" {
-            let content = field
-                .map_ok(|chunk| BytesMut::from(&chunk[..]))
-                .try_concat()
-                .await?;
-            let mut reader = csv::ReaderBuilder::new()
-                .has_headers(false)
-                .from_reader(content.as_ref());
-            let mut cs = Vec::new();
-            for record in reader.deserialize() {
-                let chair: CSVChair = record.map_err(|e| {
-                    log::error!("failed to read csv: {:?}", e);
-                    HttpResponse::InternalServerError()
-                })?;
-                cs.push(chair.into());
-            }
-            chairs = Some(cs);
+    let chair_search_condition = search_conditions.load().chair.clone();
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let name = field.content_disposition().and_then(|cd| cd.get_name().map(str::to_owned));
+        if name.as_deref() != Some("chairs") {
+            continue;
         }
-    }
-    if chairs.is_none() {
-        log::error!("failed to get from file: no chairs given");
-        return Ok(HttpResponse::BadRequest().finish());
-    }
-    let chairs = chairs.unwrap();
 
-    web::block(move || {
-        let mut conn = db.chair.get().expect("Failed to checkout database connection");
-        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
-        for chair in chairs {
-            let params: Vec<mysql::Value> = vec![
-                chair.id.into(),
-                chair.name.into(),
-                chair.description.into(),
-                chair.thumbnail.into(),
-                chair.price.into(),
-                chair.height.into(),
-                chair.width.into(),
-                chair.depth.into(),
-                chair.color.into(),
-                chair.features.into(),
-                chair.kind.into(),
-                chair.popularity.into(),
-                chair.stock.into(),
-            ];
-            tx.exec_drop("insert into chair (id, name, description, thumbnail, price, height, width, depth, color, features, kind, popularity, stock) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", params)?;
+        // Only chairs with stock are indexed, and those all stay in memory once indexed, so
+        // holding on to them until they are committed costs no more than indexing them will.
+        let mut inserted = Vec::new();
+        csv_upload::stream_rows(
+            "chairs",
+            &mut field,
+            &upload_config,
+            |chair: &CSVChair| {
+                chair_search_condition
+                    .feature
+                    .parse_features(&chair.features)
+                    .map_err(|name| format!("chair {} has unknown feature {}", chair.id, name))?;
+                if chair.stock > 0 {
+                    inserted.push(Chair::from(chair.clone()));
+                }
+                Ok(())
+            },
+            &db.chair,
+            insert_chairs,
+        )
+        .await?;
+
+        let mut index = data.chair_index.write().unwrap();
+        for chair in inserted {
+            index.insert(chair);
         }
-        tx.commit()?;
-        Ok(())
-    })
-    .await.map_err(|e: BlockingDBError| {
-        log::error!("failed to insert/commit chair: {:?}", e);
-        HttpResponse::InternalServerError()
-    })?;
-    Ok(HttpResponse::Created().finish())
+        return Ok(HttpResponse::Created().finish());
+    }
+    log::error!("failed to get from file: no chairs given");
+    Err(AppError::invalid_parameter("chairs", "no chairs given"))
+}
+
+fn insert_chairs(tx: &mut mysql::Transaction, chairs: Vec<CSVChair>) -> mysql::Result<()> {
+    let rows = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"; chairs.len()].join(", ");
+    let mut params: Vec<mysql::Value> = Vec::with_capacity(chairs.len() * CSVChair::FIELDS);
+    for chair in chairs {
+        params.extend(vec![
+            chair.id.into(),
+            chair.name.into(),
+            chair.description.into(),
+            chair.thumbnail.into(),
+            chair.price.into(),
+            chair.height.into(),
+            chair.width.into(),
+            chair.depth.into(),
+            chair.color.into(),
+            chair.features.into(),
+            chair.kind.into(),
+            chair.popularity.into(),
+            chair.stock.into(),
+        ]);
+    }
+    tx.exec_drop(format!("insert into chair (id, name, description, thumbnail, price, height, width, depth, color, features, kind, popularity, stock) values {}", rows), params)
 }
 
 #[derive(Debug, Deserialize)]
@@ -499,254 +835,476 @@ struct SearchChairsParams {
     width_range_id: String,
     #[serde(rename = "depthRangeId", default)]
     depth_range_id: String,
+    #[serde(rename = "priceMin")]
+    price_min: Option<i64>,
+    #[serde(rename = "priceMax")]
+    price_max: Option<i64>,
+    #[serde(rename = "heightMin")]
+    height_min: Option<i64>,
+    #[serde(rename = "heightMax")]
+    height_max: Option<i64>,
+    #[serde(rename = "widthMin")]
+    width_min: Option<i64>,
+    #[serde(rename = "widthMax")]
+    width_max: Option<i64>,
+    #[serde(rename = "depthMin")]
+    depth_min: Option<i64>,
+    #[serde(rename = "depthMax")]
+    depth_max: Option<i64>,
     #[serde(default)]
     kind: String,
     #[serde(default)]
     color: String,
     #[serde(default)]
     features: String,
-    page: i64,
-    #[serde(rename = "perPage")]
-    per_page: i64,
+    #[serde(default)]
+    sort: String,
 }
 
 #[derive(Debug, Serialize)]
 struct ChairSearchResponse {
-    count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<i64>,
     chairs: Vec<Chair>,
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 async fn search_chairs(
-    chair_search_condition: web::Data<Arc<ChairSearchCondition>>,
-    db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
     query_params: web::Query<SearchChairsParams>,
-) -> Result<HttpResponse, AWError> {
+    pagination: Pagination,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/chair/search");
 
-    let mut conditions = Vec::new();
-    let mut params: Vec<mysql::Value> = Vec::new();
+    // Range ids must be resolved against the conditions the index was bucketed with.
+    let index = data.chair_index.read().unwrap();
+    let chair_search_condition = index.condition();
+    let mut query = ChairQuery {
+        price: parse_range_ids(
+            &chair_search_condition.price,
+            "priceRangeId",
+            &query_params.price_range_id,
+        )?,
+        height: parse_range_ids(
+            &chair_search_condition.height,
+            "heightRangeId",
+            &query_params.height_range_id,
+        )?,
+        width: parse_range_ids(
+            &chair_search_condition.width,
+            "widthRangeId",
+            &query_params.width_range_id,
+        )?,
+        depth: parse_range_ids(
+            &chair_search_condition.depth,
+            "depthRangeId",
+            &query_params.depth_range_id,
+        )?,
+        price_bounds: parse_bounds(
+            "priceMin",
+            query_params.price_min,
+            "priceMax",
+            query_params.price_max,
+        )?,
+        height_bounds: parse_bounds(
+            "heightMin",
+            query_params.height_min,
+            "heightMax",
+            query_params.height_max,
+        )?,
+        width_bounds: parse_bounds(
+            "widthMin",
+            query_params.width_min,
+            "widthMax",
+            query_params.width_max,
+        )?,
+        depth_bounds: parse_bounds(
+            "depthMin",
+            query_params.depth_min,
+            "depthMax",
+            query_params.depth_max,
+        )?,
+        kind: split_list(&query_params.kind),
+        color: split_list(&query_params.color),
+        sort: ChairSort::from_param(&query_params.sort).ok_or_else(|| {
+            log::info!("sort invalid, {} : Unknown sort", query_params.sort);
+            AppError::invalid_parameter("sort", format!("must be one of {}", ChairSort::NAMES))
+        })?,
+        ..ChairQuery::default()
+    };
 
-    if !query_params.price_range_id.is_empty() {
-        if let Some(chair_price) =
-            get_range(&chair_search_condition.price, &query_params.price_range_id)
-        {
-            if chair_price.min != -1 {
-                conditions.push("price >= ?");
-                params.push(chair_price.min.into());
-            }
-            if chair_price.max != -1 {
-                conditions.push("price < ?");
-                params.push(chair_price.max.into());
+    if !query_params.features.is_empty() {
+        match chair_search_condition.feature.parse_feature_names(split_list(&query_params.features)) {
+            Ok(features) => query.features = features,
+            Err(name) => {
+                log::info!("features invalid, {} : Unexpected feature", name);
+                return Err(AppError::invalid_parameter(
+                    "features",
+                    format!("unexpected feature {}", name),
+                ));
             }
-        } else {
-            log::info!(
-                "priceRangeID invalid, {} : Unexpected Range ID",
-                query_params.price_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
         }
     }
 
-    if !query_params.height_range_id.is_empty() {
-        if let Some(chair_height) = get_range(
-            &chair_search_condition.height,
-            &query_params.height_range_id,
-        ) {
-            if chair_height.min != -1 {
-                conditions.push("height >= ?");
-                params.push(chair_height.min.into());
-            }
-            if chair_height.max != -1 {
-                conditions.push("height < ?");
-                params.push(chair_height.max.into());
-            }
-        } else {
-            log::info!(
-                "heightRangeId invalid, {} : Unexpected Range ID",
-                query_params.height_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
-        }
+    if query.is_empty() {
+        log::info!("Search condition not found");
+        return Err(AppError::NoSearchCondition);
     }
 
-    if !query_params.width_range_id.is_empty() {
-        if let Some(chair_width) =
-            get_range(&chair_search_condition.width, &query_params.width_range_id)
-        {
-            if chair_width.min != -1 {
-                conditions.push("width >= ?");
-                params.push(chair_width.min.into());
-            }
-            if chair_width.max != -1 {
-                conditions.push("width < ?");
-                params.push(chair_width.max.into());
+    let start = pagination.start(query.sort.name())?;
+    let (count, chairs) = if pagination.with_count {
+        let (count, chairs) = index.search(&query, start, pagination.per_page);
+        (Some(count), chairs)
+    } else {
+        (None, index.page(&query, start, pagination.per_page))
+    };
+    let next_cursor = pagination.next_cursor(
+        query.sort.name(),
+        chairs.len(),
+        chairs.last().map(|chair| query.sort.key(chair)),
+    );
+    Ok(HttpResponse::Ok().json(ChairSearchResponse {
+        count,
+        chairs,
+        next_cursor,
+    }))
+}
+
+/// Finds the range whose declared `id` is `range_id` and returns its position in `cond.ranges`.
+fn get_range_index(cond: &RangeCondition, range_id: &str) -> Option<usize> {
+    let range_id: i64 = range_id.parse().ok()?;
+    cond.ranges.iter().position(|range| range.id == range_id)
+}
+
+/// Resolves a comma-separated list of range ids, such as `1` or `1,2`, to sorted positions
+/// in `cond.ranges`. An empty parameter selects nothing.
+fn parse_range_ids(
+    cond: &RangeCondition,
+    param: &'static str,
+    range_ids: &str,
+) -> Result<Vec<usize>, AppError> {
+    let mut indexes = Vec::new();
+    for range_id in split_list(range_ids) {
+        match get_range_index(cond, range_id) {
+            Some(index) => indexes.push(index),
+            None => {
+                log::info!("{} invalid, {} : Unexpected Range ID", param, range_ids);
+                return Err(AppError::invalid_parameter(
+                    param,
+                    format!("unexpected range id {}", range_id),
+                ));
             }
-        } else {
-            log::info!(
-                "widthRangeId invalid, {} : Unexpected Range ID",
-                query_params.width_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
         }
     }
+    indexes.sort_unstable();
+    indexes.dedup();
+    Ok(indexes)
+}
 
-    if !query_params.depth_range_id.is_empty() {
-        if let Some(chair_depth) =
-            get_range(&chair_search_condition.depth, &query_params.depth_range_id)
-        {
-            if chair_depth.min != -1 {
-                conditions.push("depth >= ?");
-                params.push(chair_depth.min.into());
-            }
-            if chair_depth.max != -1 {
-                conditions.push("depth < ?");
-                params.push(chair_depth.max.into());
-            }
-        } else {
-            log::info!(
-                "depthRangeId invalid, {} : Unexpected Range ID",
-                query_params.depth_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
+/// Validates explicit bounds such as `priceMin`/`priceMax`. Both are optional; `max` is
+/// exclusive like the fixture ranges. When range ids are given for the same dimension too,
+/// results must satisfy both.
+fn parse_bounds(
+    min_param: &'static str,
+    min: Option<i64>,
+    max_param: &'static str,
+    max: Option<i64>,
+) -> Result<Bounds, AppError> {
+    for &(param, value) in &[(min_param, min), (max_param, max)] {
+        if let Some(value) = value.filter(|&v| v < 0) {
+            log::info!("{} invalid, {} : Negative value", param, value);
+            return Err(AppError::invalid_parameter(param, "must not be negative"));
         }
     }
+    if let (Some(min), Some(max)) = (min, max) {
+        if min >= max {
+            log::info!("{} invalid, {} : Not greater than {} {}", max_param, max, min_param, min);
+            return Err(AppError::invalid_parameter(
+                max_param,
+                format!("must be greater than {}", min_param),
+            ));
+        }
+    }
+    Ok(Bounds { min, max })
+}
+
+/// Splits a comma-separated multi-value parameter such as `kind`, `features` or a range id
+/// list. Entries are trimmed, so `a, b` means `a` and `b`, and empty ones are dropped.
+fn split_list(values: &str) -> Vec<&str> {
+    values.split(',').map(str::trim).filter(|v| !v.is_empty()).collect()
+}
+
+#[derive(Debug, Serialize)]
+struct ChairListResponse {
+    chairs: Vec<Chair>,
+}
+
+async fn get_low_priced_chair(data: web::Data<AppCache>) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("GET /api/chair/low_priced");
 
-    if !query_params.kind.is_empty() {
-        conditions.push("kind = ?");
-        params.push(query_params.kind.clone().into());
+    let chairs = data.chair_index.read().unwrap().low_priced(LIMIT as usize);
+    Ok(HttpResponse::Ok().json(ChairListResponse { chairs }))
+}
+
+async fn get_chair_search_condition(
+    req: HttpRequest,
+    search_conditions: web::Data<SearchConditionStore>,
+) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("GET /api/chair/search/condition");
+
+    Ok(search_conditions.load().chair_json.respond(&req))
+}
+
+#[derive(Debug, Deserialize)]
+struct BuyChairRequest {
+    email: String,
+}
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+#[derive(Debug, Serialize)]
+struct ChairPurchase {
+    id: i64,
+    #[serde(rename = "chairId")]
+    chair_id: i64,
+    email: String,
+    #[serde(rename = "purchasedAt")]
+    purchased_at: chrono::NaiveDateTime,
+    #[serde(rename = "canceledAt")]
+    canceled_at: Option<chrono::NaiveDateTime>,
+}
+
+impl FromRow for ChairPurchase {
+    fn from_row_opt(row: mysql::Row) -> Result<Self, mysql::FromRowError> {
+        fn convert(row: &mysql::Row) -> Result<ChairPurchase, ()> {
+            Ok(ChairPurchase {
+                id: row.get("id").ok_or(())?,
+                chair_id: row.get("chair_id").ok_or(())?,
+                email: row.get("email").ok_or(())?,
+                purchased_at: row.get("purchased_at").ok_or(())?,
+                canceled_at: row.get("canceled_at").ok_or(())?,
+            })
+        }
+        convert(&row).map_err(|_| mysql::FromRowError(row))
     }
+}
 
-    if !query_params.color.is_empty() {
-        conditions.push("color = ?");
-        params.push(query_params.color.clone().into());
+fn validate_email(email: &str) -> Result<(), AppError> {
+    // Addresses end up in mail headers and SMTP commands, so control characters are rejected too.
+    if email.is_empty()
+        || email.len() > 255
+        || !email.contains('@')
+        || email.chars().any(|c| c.is_control() || c == '<' || c == '>')
+    {
+        log::info!("email invalid, {} : Malformed address", email);
+        return Err(AppError::invalid_parameter("email", "malformed email address"));
     }
+    Ok(())
+}
 
-    if !query_params.features.is_empty() {
-        for f in query_params.features.split(',') {
-            conditions.push("features like concat('%', ?, '%')");
-            params.push(f.into());
+fn get_idempotency_key(req: &HttpRequest) -> Result<Option<String>, AppError> {
+    let value = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+            Ok(Some(key.to_owned()))
         }
+        _ => Err(AppError::invalid_parameter(
+            IDEMPOTENCY_KEY_HEADER,
+            "must be 1 to 128 visible ASCII characters",
+        )),
     }
+}
 
-    if conditions.is_empty() {
-        log::info!("Search condition not found");
-        return Ok(HttpResponse::BadRequest().finish());
-    }
+enum BuyChairOutcome {
+    Purchased(ChairPurchase),
+    Replayed(ChairPurchase),
+    SoldOut,
+    NotFound,
+}
 
-    conditions.push("stock > 0");
+async fn buy_chair(
+    db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
+    req: HttpRequest,
+    path: web::Path<(i64,)>,
+    params: web::Json<BuyChairRequest>,
+) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("POST /api/chair/buy/{id}");
 
-    let per_page = query_params.per_page;
-    let page = query_params.page;
+    let id = path.0;
+    let email = params.into_inner().email;
+    validate_email(&email)?;
+    let idempotency_key = get_idempotency_key(&req)?;
 
-    let search_condition = conditions.join(" and ");
-    let res = web::block(move || {
-        let mut conn = db.chair.get().expect("Failed to checkout database connection");
-        let row = conn.exec_first(
-            format!("select count(*) from chair where {}", search_condition),
-            &params,
-        )?;
-        let count = row.map(|(c,)| c).unwrap_or(0);
-
-        params.push(per_page.into());
-        params.push((page * per_page).into());
-        let chairs = conn.exec(
-            format!(
-                "select * from chair where {} order by popularity desc, id desc limit ? offset ?",
-                search_condition
-            ),
-            &params,
-        )?;
-        Ok(ChairSearchResponse { count, chairs })
+    let outcome = web::block(move || {
+        let mut conn = db.chair.get()?;
+        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
+        // Locking the chair first serializes retries of the same purchase.
+        let row: Option<Chair> = tx.exec_first("select * from chair where id = ? for update", (id,))?;
+        let chair = match row {
+            Some(chair) => chair,
+            None => return Ok(BuyChairOutcome::NotFound),
+        };
+        if let Some(key) = &idempotency_key {
+            let previous: Option<ChairPurchase> = tx.exec_first(
+                "select * from chair_purchase where idempotency_key = ? for update",
+                (key,),
+            )?;
+            if let Some(previous) = previous {
+                if previous.chair_id != id || previous.email != email {
+                    return Err(AppError::IdempotencyKeyReused(key.clone()));
+                }
+                return Ok(BuyChairOutcome::Replayed(previous));
+            }
+        }
+        if chair.stock <= 0 {
+            return Ok(BuyChairOutcome::SoldOut);
+        }
+        tx.exec_drop("update chair set stock = stock - 1 where id = ?", (id,))?;
+        // A concurrent purchase of another chair may have taken the key since it was checked.
+        tx.exec_drop(
+            "insert into chair_purchase (chair_id, email, idempotency_key) values (?, ?, ?)",
+            (id, &email, &idempotency_key),
+        )
+        .map_err(|e| match idempotency_key {
+            Some(key) if error::is_duplicate_key(&e) => AppError::IdempotencyKeyReused(key),
+            _ => AppError::from(e),
+        })?;
+        let purchase_id = tx.last_insert_id();
+        let purchase: Option<ChairPurchase> =
+            tx.exec_first("select * from chair_purchase where id = ?", (purchase_id,))?;
+        tx.commit()?;
+        purchase
+            .map(BuyChairOutcome::Purchased)
+            .ok_or_else(|| AppError::Internal("inserted purchase not found".to_owned()))
     })
     .await
     .map_err(|e: BlockingDBError| {
-        log::error!("searchChairs DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        log::error!("buy_chair DB execution error : {:?}", e);
+        AppError::from(e)
     })?;
-    Ok(HttpResponse::Ok().json(res))
-}
 
-fn get_range<'a>(cond: &'a RangeCondition, range_id: &str) -> Option<&'a Range> {
-    range_id.parse().ok().and_then(|range_index| {
-        if range_index < 0 || cond.ranges.len() as i64 <= range_index {
-            None
-        } else {
-            Some(&cond.ranges[range_index as usize])
+    match outcome {
+        BuyChairOutcome::Purchased(purchase) => {
+            data.chair_index.write().unwrap().decrement_stock(id);
+            Ok(HttpResponse::Ok().json(purchase))
         }
-    })
+        BuyChairOutcome::Replayed(purchase) => {
+            log::info!("buy_chair replayed purchase : {}", purchase.id);
+            Ok(HttpResponse::Ok().json(purchase))
+        }
+        BuyChairOutcome::SoldOut => {
+            log::info!("buy_chair requested chair is sold out : {}", id);
+            Err(AppError::ChairSoldOut(id))
+        }
+        BuyChairOutcome::NotFound => {
+            log::info!("buy_chair requested chair not found : {}", id);
+            Err(AppError::ChairNotFound(id))
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct ChairListResponse {
-    chairs: Vec<Chair>,
+#[derive(Debug, Deserialize)]
+struct ListChairPurchasesParams {
+    email: String,
 }
 
-async fn get_low_priced_chair(db: web::Data<MultiPool>) -> Result<HttpResponse, AWError> {
-    newrelic_transaction!("GET /api/chair/low_priced");
+#[derive(Debug, Serialize)]
+struct ChairPurchaseListResponse {
+    purchases: Vec<ChairPurchase>,
+}
 
-    let chairs = web::block(move || {
-        let mut conn = db.chair.get().expect("Failed to checkout database connection");
-        conn.exec(
-            "select * from chair where stock > 0 order by price asc, id asc limit ?",
-            (LIMIT,),
-        )
+async fn get_chair_purchases(
+    db: web::Data<MultiPool>,
+    query_params: web::Query<ListChairPurchasesParams>,
+) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("GET /api/chair/purchases");
+
+    let email = query_params.into_inner().email;
+    validate_email(&email)?;
+
+    let purchases = web::block(move || {
+        let mut conn = db.chair.get()?;
+        Ok(conn.exec(
+            "select * from chair_purchase where email = ? order by id desc",
+            (email,),
+        )?)
     })
     .await
-    .map_err(|e| {
-        log::error!("get_low_priced_chair DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+    .map_err(|e: BlockingDBError| {
+        log::error!("get_chair_purchases DB execution error : {:?}", e);
+        AppError::from(e)
     })?;
 
-    Ok(HttpResponse::Ok().json(ChairListResponse { chairs }))
-}
-
-async fn get_chair_search_condition(
-    chair_search_condition: web::Data<Arc<ChairSearchCondition>>,
-) -> Result<HttpResponse, AWError> {
-    newrelic_transaction!("GET /api/chair/search/condition");
-
-    Ok(HttpResponse::Ok().json(chair_search_condition.as_ref().as_ref()))
+    Ok(HttpResponse::Ok().json(ChairPurchaseListResponse { purchases }))
 }
 
 #[derive(Debug, Deserialize)]
-struct BuyChairRequest {
+struct CancelChairPurchaseRequest {
     email: String,
 }
 
-async fn buy_chair(
+async fn cancel_chair_purchase(
     db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
     path: web::Path<(i64,)>,
-    _params: web::Json<BuyChairRequest>,
-) -> Result<HttpResponse, AWError> {
-    newrelic_transaction!("POST /api/chair/buy/{id}");
+    params: web::Json<CancelChairPurchaseRequest>,
+) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("POST /api/chair/purchases/{id}/cancel");
 
     let id = path.0;
+    let email = params.into_inner().email;
+    validate_email(&email)?;
 
-    let found: bool = web::block(move || {
-        let mut conn = db.chair.get().expect("Failed to checkout database connection");
+    let canceled = web::block(move || {
+        let mut conn = db.chair.get()?;
         let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
-        let row: Option<Chair> = tx.exec_first(
-            "select * from chair where id = ? and stock > 0 for update",
+        let purchase: Option<ChairPurchase> =
+            tx.exec_first("select * from chair_purchase where id = ? for update", (id,))?;
+        let purchase = match purchase {
+            Some(purchase) if purchase.email == email => purchase,
+            _ => return Ok(None),
+        };
+        if purchase.canceled_at.is_some() {
+            // Canceling twice must not restore the stock twice.
+            return Ok(Some((purchase, None)));
+        }
+        let chair: Option<Chair> = tx.exec_first(
+            "select * from chair where id = ? for update",
+            (purchase.chair_id,),
+        )?;
+        tx.exec_drop(
+            "update chair set stock = stock + 1 where id = ?",
+            (purchase.chair_id,),
+        )?;
+        tx.exec_drop(
+            "update chair_purchase set canceled_at = now(6) where id = ?",
             (id,),
         )?;
-        if row.is_some() {
-            tx.exec_drop("update chair set stock = stock - 1 where id = ?", (id,))?;
-            tx.commit()?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+        let purchase: Option<ChairPurchase> =
+            tx.exec_first("select * from chair_purchase where id = ?", (id,))?;
+        tx.commit()?;
+        Ok(purchase.map(|purchase| (purchase, chair)))
     })
     .await
     .map_err(|e: BlockingDBError| {
-        log::error!("buy_chair DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        log::error!("cancel_chair_purchase DB execution error : {:?}", e);
+        AppError::from(e)
     })?;
 
-    if found {
-        Ok(HttpResponse::Ok().finish())
+    if let Some((purchase, restocked)) = canceled {
+        if let Some(chair) = restocked {
+            data.chair_index.write().unwrap().increment_stock(chair);
+        }
+        Ok(HttpResponse::Ok().json(purchase))
     } else {
-        Ok(HttpResponse::NotFound().finish())
+        log::info!("cancel_chair_purchase requested purchase not found : {}", id);
+        Err(AppError::PurchaseNotFound(id))
     }
 }
 
@@ -794,29 +1352,29 @@ impl FromRow for Estate {
 async fn get_estate_detail(
     db: web::Data<MultiPool>,
     path: web::Path<(i64,)>,
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/estate/{id}");
 
     let id = path.0;
 
     let estate: Option<Estate> = web::block(move || {
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        conn.exec_first("select * from estate where id = ?", (id,))
+        let mut conn = db.estate.get()?;
+        Ok(conn.exec_first("select * from estate where id = ?", (id,))?)
     })
     .await
-    .map_err(|e| {
+    .map_err(|e: BlockingDBError| {
         log::error!("Database Execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        AppError::from(e)
     })?;
 
     if let Some(estate) = estate {
         Ok(HttpResponse::Ok().json(estate))
     } else {
-        Ok(HttpResponse::NotFound().finish())
+        Err(AppError::EstateNotFound(id))
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct CSVEstate {
     id: i64,
     name: String,
@@ -832,86 +1390,147 @@ struct CSVEstate {
     popularity: i64,
 }
 
-impl Into<Estate> for CSVEstate {
-    fn into(self) -> Estate {
+impl CsvRow for CSVEstate {
+    const FIELDS: usize = 12;
+    // Also the feature mask, and the latitude and longitude again for the location point.
+    const PLACEHOLDERS: usize = Self::FIELDS + 3;
+}
+
+impl From<CSVEstate> for Estate {
+    fn from(csv: CSVEstate) -> Self {
         Estate {
-            id: self.id,
-            name: self.name,
-            description: self.description,
-            thumbnail: self.thumbnail,
-            address: self.address,
-            latitude: self.latitude,
-            longitude: self.longitude,
-            rent: self.rent,
-            door_height: self.door_height,
-            door_width: self.door_width,
-            features: self.features,
-            popularity: self.popularity,
+            id: csv.id,
+            name: csv.name,
+            description: csv.description,
+            thumbnail: csv.thumbnail,
+            address: csv.address,
+            latitude: csv.latitude,
+            longitude: csv.longitude,
+            rent: csv.rent,
+            door_height: csv.door_height,
+            door_width: csv.door_width,
+            features: csv.features,
+            popularity: csv.popularity,
         }
     }
 }
 
 async fn post_estate(
-    db: web::Data<MultiPool>, 
-    data: web::Data<AppCache>, 
+    db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
+    search_conditions: web::Data<SearchConditionStore>,
+    upload_config: web::Data<UploadConfig>,
     mut payload: Multipart
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("POST /api/estate");
 
-    let mut estates: Option<Vec<Estate>> = None;
-    while let Ok(Some(field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition().unwrap();
-        let name = content_disposition.get_name().unwrap();
-        if name == "estates" {
-            let content = field
-                .map_ok(|chunk| BytesMut::from(&chunk[..]))
-                .try_concat()
-                .await?;
-            let mut reader = csv::ReaderBuilder::new()
-                .has_headers(false)
-                .from_reader(content.as_ref());
-            let mut es = Vec::new();
-            for record in reader.deserialize() {
-                let estate: CSVEstate = record.map_err(|e| {
-                    log::error!("failed to read csv: {:?}", e);
-                    HttpResponse::InternalServerError()
-                })?;
-                es.push(estate.into());
-            }
-            estates = Some(es);
+    let estate_search_condition = search_conditions.load().estate.clone();
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let name = field.content_disposition().and_then(|cd| cd.get_name().map(str::to_owned));
+        if name.as_deref() != Some("estates") {
+            continue;
         }
-    }
-    if estates.is_none() {
-        log::error!("failed to get from file: no estates given");
-        return Ok(HttpResponse::BadRequest().finish());
-    }
-    let estates = estates.unwrap();
 
-    web::block(move || {
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
-        for estate in estates {
-            let query = format!("insert into estate (id, name, description, thumbnail, address, latitude, longitude, rent, door_height, door_width, features, popularity, location) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, Point({}, {}))", estate.latitude, estate.longitude);
-            tx.exec_drop(query, (estate.id, estate.name, estate.description, estate.thumbnail, estate.address, estate.latitude, estate.longitude, estate.rent, estate.door_height, estate.door_width, estate.features, estate.popularity))?;
+        // Only what the caches need is kept: every location, and the cheapest estates.
+        let mut locations = Vec::new();
+        let mut cheapest: Vec<Estate> = Vec::new();
+        csv_upload::stream_rows(
+            "estates",
+            &mut field,
+            &upload_config,
+            |estate: &CSVEstate| {
+                estate_search_condition
+                    .feature
+                    .parse_features(&estate.features)
+                    .map_err(|name| format!("estate {} has unknown feature {}", estate.id, name))?;
+                let location = Coordinate {
+                    latitude: estate.latitude,
+                    longitude: estate.longitude,
+                };
+                location.validate().map_err(|e| {
+                    format!("estate {} has invalid location: {}", estate.id, e)
+                })?;
+                locations.push((estate.id, estate.popularity, location));
+                cheapest.push(estate.clone().into());
+                if cheapest.len() >= 2 * LIMIT as usize {
+                    cheapest.sort_by_key(|e| (e.rent, e.id));
+                    cheapest.truncate(LIMIT as usize);
+                }
+                Ok(())
+            },
+            &db.estate,
+            {
+                let condition = estate_search_condition.clone();
+                move |tx: &mut mysql::Transaction, estates| {
+                    insert_estates(tx, &condition.feature, estates)
+                }
+            },
+        )
+        .await?;
+        data.estate_counts.invalidate();
+
+        let mut index = data.estate_locations.write().unwrap();
+        for (id, popularity, location) in locations {
+            index.insert(id, popularity, location);
         }
-        tx.commit()?;
+        drop(index);
+
+        // Estates are never updated or deleted, so merging the new ones into the cached
+        // list gives the same result as re-running the query.
+        data.low_priced_estates.update(|current| {
+            let mut estates: Vec<Estate> =
+                current.estates.iter().chain(&cheapest).cloned().collect();
+            estates.sort_by_key(|e| (e.rent, e.id));
+            estates.dedup_by_key(|e| e.id);
+            estates.truncate(LIMIT as usize);
+            EstateListResponse { estates }
+        });
+        return Ok(HttpResponse::Created().finish());
+    }
+    log::error!("failed to get from file: no estates given");
+    Err(AppError::invalid_parameter("estates", "no estates given"))
+}
 
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        let estates = conn.exec(
-            "select * from estate order by rent asc, id asc limit ?",
-            (LIMIT,),
-        )?;
-        let mut cache = data.low_priced_estates.lock().unwrap();
-        *cache = estates;
+/// Fills `features_mask` for estates loaded by SQL scripts, which only set `features`.
+fn fill_estate_feature_masks<C: Queryable>(
+    conn: &mut C,
+    features: &ListCondition,
+) -> mysql::Result<()> {
+    let mut params = Vec::new();
+    let mask = features.stored_sql("features", &mut params);
+    conn.exec_drop(
+        format!("update estate set features_mask = {} where features_mask is null", mask),
+        params,
+    )
+}
 
-        Ok(())
-    }).await.map_err(
-        |e: BlockingDBError| {
-            log::error!("failed to insert/commit estate: {:?}", e);
-            HttpResponse::InternalServerError()
-        },
-    )?;
-    Ok(HttpResponse::Created().finish())
+fn insert_estates(
+    tx: &mut mysql::Transaction,
+    features: &ListCondition,
+    estates: Vec<CSVEstate>,
+) -> mysql::Result<()> {
+    let rows = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, Point(?, ?))"; estates.len()].join(", ");
+    let mut params: Vec<mysql::Value> = Vec::with_capacity(estates.len() * CSVEstate::PLACEHOLDERS);
+    for estate in estates {
+        params.extend(vec![
+            estate.id.into(),
+            estate.name.into(),
+            estate.description.into(),
+            estate.thumbnail.into(),
+            estate.address.into(),
+            estate.latitude.into(),
+            estate.longitude.into(),
+            estate.rent.into(),
+            estate.door_height.into(),
+            estate.door_width.into(),
+            features.parse_features_lossy(&estate.features).stored().into(),
+            estate.features.into(),
+            estate.popularity.into(),
+            estate.latitude.into(),
+            estate.longitude.into(),
+        ]);
+    }
+    tx.exec_drop(format!("insert into estate (id, name, description, thumbnail, address, latitude, longitude, rent, door_height, door_width, features_mask, features, popularity, location) values {}", rows), params)
 }
 
 #[derive(Debug, Deserialize)]
@@ -922,137 +1541,238 @@ struct SearchEstatesParams {
     door_width_range_id: String,
     #[serde(rename = "rentRangeId", default)]
     rent_range_id: String,
+    #[serde(rename = "doorHeightMin")]
+    door_height_min: Option<i64>,
+    #[serde(rename = "doorHeightMax")]
+    door_height_max: Option<i64>,
+    #[serde(rename = "doorWidthMin")]
+    door_width_min: Option<i64>,
+    #[serde(rename = "doorWidthMax")]
+    door_width_max: Option<i64>,
+    #[serde(rename = "rentMin")]
+    rent_min: Option<i64>,
+    #[serde(rename = "rentMax")]
+    rent_max: Option<i64>,
     #[serde(default)]
     features: String,
-    page: i64,
-    #[serde(rename = "perPage")]
-    per_page: i64,
+    #[serde(default)]
+    sort: String,
 }
 
 #[derive(Debug, Serialize)]
 struct EstateSearchResponse {
-    count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<i64>,
     estates: Vec<Estate>,
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 async fn search_estates(
-    estate_search_condition: web::Data<Arc<EstateSearchCondition>>,
+    search_conditions: web::Data<SearchConditionStore>,
     db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
     query_params: web::Query<SearchEstatesParams>,
-) -> Result<HttpResponse, AWError> {
+    pagination: Pagination,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/estate/search");
 
+    let estate_search_condition = search_conditions.load().estate.clone();
+    let sort = EstateSort::from_param(&query_params.sort).ok_or_else(|| {
+        log::info!("sort invalid, {} : Unknown sort", query_params.sort);
+        AppError::invalid_parameter("sort", format!("must be one of {}", EstateSort::NAMES))
+    })?;
     let mut conditions = Vec::new();
     let mut params: Vec<mysql::Value> = Vec::new();
 
-    if !query_params.door_height_range_id.is_empty() {
-        if let Some(door_height) = get_range(
+    push_range_conditions(
+        "door_height",
+        &estate_search_condition.door_height,
+        &parse_range_ids(
             &estate_search_condition.door_height,
+            "doorHeightRangeId",
             &query_params.door_height_range_id,
-        ) {
-            if door_height.min != -1 {
-                conditions.push("door_height >= ?");
-                params.push(door_height.min.into());
-            }
-            if door_height.max != -1 {
-                conditions.push("door_height < ?");
-                params.push(door_height.max.into());
-            }
-        } else {
-            log::info!(
-                "doorHeightRangeID invalid, {} : Unexpected Range ID",
-                query_params.door_height_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
-        }
-    }
-
-    if !query_params.door_width_range_id.is_empty() {
-        if let Some(door_width) = get_range(
+        )?,
+        parse_bounds(
+            "doorHeightMin",
+            query_params.door_height_min,
+            "doorHeightMax",
+            query_params.door_height_max,
+        )?,
+        &mut conditions,
+        &mut params,
+    );
+    push_range_conditions(
+        "door_width",
+        &estate_search_condition.door_width,
+        &parse_range_ids(
             &estate_search_condition.door_width,
+            "doorWidthRangeId",
             &query_params.door_width_range_id,
-        ) {
-            if door_width.min != -1 {
-                conditions.push("door_width >= ?");
-                params.push(door_width.min.into());
-            }
-            if door_width.max != -1 {
-                conditions.push("door_width < ?");
-                params.push(door_width.max.into());
-            }
-        } else {
-            log::info!(
-                "doorWidthRangeID invalid, {} : Unexpected Range ID",
-                query_params.door_width_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
-        }
-    }
+        )?,
+        parse_bounds(
+            "doorWidthMin",
+            query_params.door_width_min,
+            "doorWidthMax",
+            query_params.door_width_max,
+        )?,
+        &mut conditions,
+        &mut params,
+    );
+    push_range_conditions(
+        "rent",
+        &estate_search_condition.rent,
+        &parse_range_ids(
+            &estate_search_condition.rent,
+            "rentRangeId",
+            &query_params.rent_range_id,
+        )?,
+        parse_bounds(
+            "rentMin",
+            query_params.rent_min,
+            "rentMax",
+            query_params.rent_max,
+        )?,
+        &mut conditions,
+        &mut params,
+    );
 
-    if !query_params.rent_range_id.is_empty() {
-        if let Some(estate_rent) =
-            get_range(&estate_search_condition.rent, &query_params.rent_range_id)
-        {
-            if estate_rent.min != -1 {
-                conditions.push("rent >= ?");
-                params.push(estate_rent.min.into());
+    if !query_params.features.is_empty() {
+        match estate_search_condition.feature.parse_feature_names(split_list(&query_params.features)) {
+            Ok(features) if !features.is_empty() => {
+                conditions.push("features_mask & ? = ?".to_owned());
+                params.push(features.stored().into());
+                params.push(features.stored().into());
             }
-            if estate_rent.max != -1 {
-                conditions.push("rent < ?");
-                params.push(estate_rent.max.into());
+            Ok(_) => {}
+            Err(name) => {
+                log::info!("features invalid, {} : Unexpected feature", name);
+                return Err(AppError::invalid_parameter(
+                    "features",
+                    format!("unexpected feature {}", name),
+                ));
             }
-        } else {
-            log::info!(
-                "rentRangeID invalid, {} : Unexpected Range ID",
-                query_params.rent_range_id
-            );
-            return Ok(HttpResponse::BadRequest().finish());
-        }
-    }
-
-    if !query_params.features.is_empty() {
-        for f in query_params.features.split(',') {
-            conditions.push("features like concat('%', ?, '%')");
-            params.push(f.into());
         }
     }
 
     if conditions.is_empty() {
         log::info!("search_estates search condition not found");
-        return Ok(HttpResponse::BadRequest().finish());
+        return Err(AppError::NoSearchCondition);
     }
 
-    let per_page = query_params.per_page;
-    let page = query_params.page;
+    let start = pagination.start(sort.name())?;
 
     let search_condition = conditions.join(" and ");
     let res = web::block(move || {
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        let row = conn.exec_first(
-            format!("select count(*) from estate where {}", search_condition),
-            &params,
-        )?;
-        let count = row.map(|(c,)| c).unwrap_or(0);
-
-        params.push(per_page.into());
-        params.push((page * per_page).into());
-        let estates = conn.exec(
-            format!(
-                "select * from estate where {} order by popularity desc, id desc limit ? offset ?",
-                search_condition
-            ),
-            &params,
-        )?;
-        Ok(EstateSearchResponse { count, estates })
+        let mut conn = db.estate.get()?;
+        fetch_estate_page(
+            &mut *conn,
+            &data.estate_counts,
+            &search_condition,
+            params,
+            sort,
+            start,
+            &pagination,
+        )
     })
     .await
     .map_err(|e: BlockingDBError| {
         log::error!("search_estates DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        AppError::from(e)
     })?;
     Ok(HttpResponse::Ok().json(res))
 }
 
+/// Counts the estates matching `search_condition`, through `counts`, and fetches the page
+/// at `start` in `sort` order together with the cursor to the next one.
+fn fetch_estate_page<C: Queryable>(
+    conn: &mut C,
+    counts: &CountCache,
+    search_condition: &str,
+    mut params: Vec<mysql::Value>,
+    sort: EstateSort,
+    start: PageStart,
+    pagination: &Pagination,
+) -> Result<EstateSearchResponse, AppError> {
+    let count = if pagination.with_count {
+        // Keyed by the condition before the cursor is added: the count covers every match.
+        let key = format!("{}|{:?}", search_condition, params);
+        match counts.get(&key) {
+            Ok(count) => Some(count),
+            Err(generation) => {
+                let row = conn.exec_first(
+                    format!("select count(*) from estate where {}", search_condition),
+                    &params,
+                )?;
+                let count = row.map(|(c,)| c).unwrap_or(0);
+                counts.insert(generation, key, count);
+                Some(count)
+            }
+        }
+    } else {
+        None
+    };
+
+    let (seek, offset) = match start {
+        PageStart::Offset(offset) => (String::new(), offset),
+        PageStart::After(key) => (format!(" and {}", sort.seek(key, &mut params)), 0),
+    };
+    params.push(pagination.per_page.into());
+    params.push(offset.into());
+    let estates: Vec<Estate> = conn.exec(
+        format!(
+            "select * from estate where {}{} order by {} limit ? offset ?",
+            search_condition,
+            seek,
+            sort.order_by()
+        ),
+        &params,
+    )?;
+    let next_cursor = pagination.next_cursor(
+        sort.name(),
+        estates.len(),
+        estates.last().map(|estate| sort.key(estate)),
+    );
+    Ok(EstateSearchResponse {
+        count,
+        estates,
+        next_cursor,
+    })
+}
+
+/// Adds the SQL conditions for one numeric dimension: `column` must fall into any of the
+/// selected ranges (ORed) and within the explicit `bounds`. The caller ANDs the dimensions.
+fn push_range_conditions(
+    column: &str,
+    cond: &RangeCondition,
+    indexes: &[usize],
+    bounds: Bounds,
+    conditions: &mut Vec<String>,
+    params: &mut Vec<mysql::Value>,
+) {
+    let mut alternatives = Vec::with_capacity(indexes.len());
+    let mut range_params = Vec::new();
+    for &index in indexes {
+        match cond.ranges[index].bounds().to_sql(column, &mut range_params) {
+            Some(sql) => alternatives.push(format!("({})", sql)),
+            // An unbounded range matches every row, and so does the whole selection.
+            None => {
+                alternatives.clear();
+                range_params.clear();
+                conditions.push("true".to_owned());
+                break;
+            }
+        }
+    }
+    if !alternatives.is_empty() {
+        conditions.push(format!("({})", alternatives.join(" or ")));
+        params.append(&mut range_params);
+    }
+    if let Some(sql) = bounds.to_sql(column, params) {
+        conditions.push(sql);
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct EstateListResponse {
     estates: Vec<Estate>,
@@ -1060,62 +1780,220 @@ struct EstateListResponse {
 
 async fn get_low_priced_estate(
     data: web::Data<AppCache>,
-    db: web::Data<MultiPool>
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/estate/low_priced");
 
-    let cached_estates = &(*data.low_priced_estates.lock().unwrap());
-    Ok(HttpResponse::Ok().json(EstateListResponse { estates: cached_estates.to_vec() }))
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(data.low_priced_estates.load().json.clone()))
 }
 
 async fn get_estate_search_condition(
-    estate_search_condition: web::Data<Arc<EstateSearchCondition>>,
-) -> Result<HttpResponse, AWError> {
+    req: HttpRequest,
+    search_conditions: web::Data<SearchConditionStore>,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/estate/search/condition");
 
-    Ok(HttpResponse::Ok().json(estate_search_condition.as_ref().as_ref()))
+    Ok(search_conditions.load().estate_json.respond(&req))
+}
+
+/// An estate whose door lets the chair through, and how the chair has to be carried.
+#[derive(Debug, Serialize)]
+struct RecommendedEstate {
+    #[serde(flatten)]
+    estate: Estate,
+    #[serde(rename = "fitOrientation")]
+    fit_orientation: Orientation,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecommendedEstateParams {
+    #[serde(rename = "rentRangeId", default)]
+    rent_range_id: String,
+    #[serde(rename = "rentMin")]
+    rent_min: Option<i64>,
+    #[serde(rename = "rentMax")]
+    rent_max: Option<i64>,
+}
+
+// `count` and `nextCursor` are only sent when the request asks for paging.
+#[derive(Debug, Serialize)]
+struct RecommendedEstateResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<i64>,
+    estates: Vec<RecommendedEstate>,
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 async fn search_recommended_estate_with_chair(
     db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
+    search_conditions: web::Data<SearchConditionStore>,
+    fit_engine: web::Data<FitEngine>,
     path: web::Path<(i64,)>,
-) -> Result<HttpResponse, AWError> {
+    query_params: web::Query<RecommendedEstateParams>,
+    pagination: Pagination,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("GET /api/recommended_estate/{id}");
 
     let id = path.0;
+    let engine = *fit_engine.get_ref();
+    // Without paging parameters the endpoint keeps returning its original LIMIT estates.
+    let pagination = pagination.or_per_page(LIMIT as usize).or_without_count();
+    let sort = EstateSort::Popularity;
+    let start = pagination.start(sort.name())?;
 
-    let estates = web::block(move || {
-        let mut conn_estate = db.estate.get().expect("Failed to checkout database connection");
-        let mut conn_chair = db.chair.get().expect("Failed to checkout database connection");
-        let chair: Option<Chair> = conn_chair.exec_first("select * from chair where id = ?", (id,))?;
-        if let Some(chair) = chair {
-            let mut whd = vec![chair.width, chair.height, chair.depth];
-            whd.sort();
-            let query = "select * from estate where (door_width >= ? and door_height >= ?) or (door_width >= ? and door_height >= ?) order by popularity desc, id desc limit ?";
-            let params: Vec<mysql::Value> = vec![
-                whd[0].into(),
-                whd[1].into(),
-                whd[1].into(),
-                whd[0].into(),
-                LIMIT.into(),
-            ];
-            Ok(Some(conn_estate.exec(query, params)?))
-        } else {
-            Ok(None)
+    let estate_search_condition = search_conditions.load().estate.clone();
+    let mut conditions = Vec::new();
+    let mut params: Vec<mysql::Value> = Vec::new();
+    push_range_conditions(
+        "rent",
+        &estate_search_condition.rent,
+        &parse_range_ids(
+            &estate_search_condition.rent,
+            "rentRangeId",
+            &query_params.rent_range_id,
+        )?,
+        parse_bounds(
+            "rentMin",
+            query_params.rent_min,
+            "rentMax",
+            query_params.rent_max,
+        )?,
+        &mut conditions,
+        &mut params,
+    );
+
+    // Only the database tells a sold-out chair from an unknown one.
+    let chair = data.chair_index.read().unwrap().get(id).cloned();
+    let chair = match chair {
+        Some(chair) => chair,
+        None => {
+            let chair_db = db.clone();
+            let exists: Option<(i64,)> = web::block(move || {
+                let mut conn = chair_db.chair.get()?;
+                Ok(conn.exec_first("select id from chair where id = ?", (id,))?)
+            })
+            .await
+            .map_err(|e: BlockingDBError| {
+                log::error!("Database execution error : {:?}", e);
+                AppError::from(e)
+            })?;
+            return Err(if exists.is_some() {
+                log::info!("requested id's chair is sold out : {}", id);
+                AppError::ChairSoldOut(id)
+            } else {
+                log::info!("requested id's chair not found : {}", id);
+                AppError::ChairNotFound(id)
+            });
         }
+    };
+
+    // Every door that fits the chair in some orientation fits its smallest face, so the
+    // database filter is exact and counts and pages can be computed there.
+    let (narrow, wide) = engine.min_opening(&chair);
+    conditions.push(
+        "((door_width >= ? and door_height >= ?) or (door_width >= ? and door_height >= ?))"
+            .to_owned(),
+    );
+    params.extend(vec![narrow.into(), wide.into(), wide.into(), narrow.into()]);
+
+    let search_condition = conditions.join(" and ");
+    let res = web::block(move || {
+        let mut conn = db.estate.get()?;
+        let page = fetch_estate_page(
+            &mut *conn,
+            &data.estate_counts,
+            &search_condition,
+            params,
+            sort,
+            start,
+            &pagination,
+        )?;
+        let estates = page
+            .estates
+            .into_iter()
+            .filter_map(|estate| {
+                let door = Door {
+                    width: estate.door_width,
+                    height: estate.door_height,
+                };
+                engine
+                    .fit(&chair, door)
+                    .map(|fit_orientation| RecommendedEstate {
+                        estate,
+                        fit_orientation,
+                    })
+            })
+            .collect();
+        Ok(RecommendedEstateResponse {
+            count: page.count,
+            estates,
+            next_cursor: page.next_cursor.filter(|_| pagination.is_requested()),
+        })
     })
     .await
     .map_err(|e: BlockingDBError| {
         log::error!("Database execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        AppError::from(e)
     })?;
+    Ok(HttpResponse::Ok().json(res))
+}
 
-    if let Some(estates) = estates {
-        Ok(HttpResponse::Ok().json(EstateListResponse { estates }))
-    } else {
-        log::info!("Requested chair id \"{}\" not found", id);
-        Ok(HttpResponse::BadRequest().finish())
-    }
+/// A chair that goes through the estate's door, and how it has to be carried.
+#[derive(Debug, Serialize)]
+struct RecommendedChair {
+    #[serde(flatten)]
+    chair: Chair,
+    #[serde(rename = "fitOrientation")]
+    fit_orientation: Orientation,
+}
+
+#[derive(Debug, Serialize)]
+struct RecommendedChairResponse {
+    chairs: Vec<RecommendedChair>,
+}
+
+async fn search_recommended_chair_with_estate(
+    db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
+    fit_engine: web::Data<FitEngine>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, AppError> {
+    newrelic_transaction!("GET /api/recommended_chair/{id}");
+
+    let id = path.0;
+
+    let door: Option<(i64, i64)> = web::block(move || {
+        let mut conn = db.estate.get()?;
+        Ok(conn.exec_first(
+            "select door_width, door_height from estate where id = ?",
+            (id,),
+        )?)
+    })
+    .await
+    .map_err(|e: BlockingDBError| {
+        log::error!("Database execution error : {:?}", e);
+        AppError::from(e)
+    })?;
+    let (width, height) = door.ok_or_else(|| {
+        log::info!("Requested estate id \"{}\" not found", id);
+        AppError::EstateNotFound(id)
+    })?;
+
+    let chairs = data
+        .chair_index
+        .read()
+        .unwrap()
+        .fitting(fit_engine.get_ref(), Door { width, height }, LIMIT as usize)
+        .into_iter()
+        .map(|(chair, fit_orientation)| RecommendedChair {
+            chair,
+            fit_orientation,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(RecommendedChairResponse { chairs }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -1123,7 +2001,7 @@ struct Coordinates {
     coordinates: Vec<Coordinate>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
 struct Coordinate {
     latitude: f64,
     longitude: f64,
@@ -1156,15 +2034,6 @@ impl Coordinates {
             },
         }
     }
-
-    fn coordinates_to_text(&self) -> String {
-        let points: Vec<_> = self
-            .coordinates
-            .iter()
-            .map(|c| format!("{} {}", c.latitude, c.longitude))
-            .collect();
-        format!("'POLYGON(({}))'", points.join(","))
-    }
 }
 
 #[derive(Debug)]
@@ -1175,32 +2044,42 @@ struct BoundingBox {
 
 async fn search_estate_nazotte(
     db: web::Data<MultiPool>,
+    data: web::Data<AppCache>,
     coordinates: web::Json<Coordinates>,
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("POST /api/estate/nazotte");
 
-    if coordinates.coordinates.is_empty() {
-        return Ok(HttpResponse::BadRequest().finish());
-    }
-    // let bounding_box = coordinates.get_bounding_box();
+    let polygon = coordinates.to_polygon().map_err(|e| {
+        log::info!("search_estate_nazotte invalid polygon : {}", e);
+        AppError::from(e)
+    })?;
 
-    let mut estates = web::block(move || {
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        let query = format!("select * from estate where ST_Contains(ST_PolygonFromText({}), location) order by popularity desc, id desc", coordinates.coordinates_to_text());
-        let estates_in_polygon: Vec<Estate> = conn.exec(query, ())?;
+    let ids = data.estate_locations.read().unwrap().search(&polygon, NAZOTTE_LIMIT);
+    let estates = if ids.is_empty() {
+        Vec::new()
+    } else {
+        web::block(move || {
+            let mut conn = db.estate.get()?;
+            let placeholders = vec!["?"; ids.len()].join(", ");
+            let query = format!(
+                "select * from estate where id in ({}) order by popularity desc, id desc",
+                placeholders
+            );
+            let estates_in_polygon: Vec<Estate> = conn.exec(query, ids)?;
 
-        Ok(estates_in_polygon)
-    })
-    .await
-    .map_err(|e: BlockingDBError| {
-        log::error!("Database execution error : {:?}", e);
-        HttpResponse::InternalServerError()
-    })?;
+            Ok(estates_in_polygon)
+        })
+        .await
+        .map_err(|e: BlockingDBError| {
+            log::error!("Database execution error : {:?}", e);
+            AppError::from(e)
+        })?
+    };
 
-    estates.truncate(NAZOTTE_LIMIT);
     Ok(HttpResponse::Ok().json(EstateSearchResponse {
-        count: estates.len() as i64,
+        count: Some(estates.len() as i64),
         estates,
+        next_cursor: None,
     }))
 }
 
@@ -1211,26 +2090,142 @@ struct PostEstateRequestDocumentParams {
 
 async fn post_estate_request_document(
     db: web::Data<MultiPool>,
+    document_env: web::Data<Arc<DocumentDeliveryEnv>>,
     path: web::Path<(i64,)>,
-    _params: web::Json<PostEstateRequestDocumentParams>,
-) -> Result<HttpResponse, AWError> {
+    params: web::Json<PostEstateRequestDocumentParams>,
+) -> Result<HttpResponse, AppError> {
     newrelic_transaction!("POST /api/estate/req_doc/{id}");
 
     let id = path.0;
+    let email = params.into_inner().email;
+    validate_email(&email)?;
+    let dedup_window_secs = document_env.dedup_window_secs;
 
-    let estate: Option<Estate> = web::block(move || {
-        let mut conn = db.estate.get().expect("Failed to checkout database connection");
-        conn.exec_first("select * from estate where id = ?", (id,))
+    web::block(move || {
+        let mut conn = db.estate.get()?;
+        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
+
+        // Locking the estate serializes concurrent requests for it, so the dedup check below holds.
+        let estate: Option<Estate> =
+            tx.exec_first("select * from estate where id = ? for update", (id,))?;
+        if estate.is_none() {
+            return Err(AppError::EstateNotFound(id));
+        }
+
+        let recent: Option<i64> = tx.exec_first(
+            "select id from document_request where estate_id = ? and email = ? and requested_at > now(6) - interval ? second limit 1",
+            (id, &email, dedup_window_secs),
+        )?;
+        if recent.is_some() {
+            log::info!("document request for estate {} by {} deduplicated", id, email);
+            return Ok(());
+        }
+
+        tx.exec_drop(
+            "insert into document_request (estate_id, email) values (?, ?)",
+            (id, &email),
+        )?;
+        let request_id = tx.last_insert_id();
+        tx.exec_drop(
+            "insert into document_request_outbox (document_request_id, estate_id, email) values (?, ?, ?)",
+            (request_id, id, &email),
+        )?;
+        tx.commit()?;
+        Ok(())
     })
     .await
-    .map_err(|e| {
+    .map_err(|e: BlockingDBError| {
         log::error!("post_estate_request_document: DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
+        AppError::from(e)
     })?;
 
-    if estate.is_some() {
-        Ok(HttpResponse::Ok().finish())
-    } else {
-        Ok(HttpResponse::NotFound().finish())
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(host: &str, db_name: &str, init_scripts: &'static [&'static str]) -> MySQLConnectionEnv {
+        MySQLConnectionEnv {
+            host: host.to_owned(),
+            port: 3306,
+            user: "isucon".to_owned(),
+            db_name: db_name.to_owned(),
+            password: "isucon".to_owned(),
+            pool_max_size: DEFAULT_POOL_MAX_SIZE,
+            pool_timeout: std::time::Duration::from_millis(DEFAULT_POOL_TIMEOUT_MS),
+            init_scripts,
+        }
+    }
+
+    #[test]
+    fn shared_database_runs_the_schema_once_then_each_shard_in_turn() {
+        let envs = MultiMySQLConnectionEnv {
+            chair: env("127.0.0.1", "isuumo", &["2_DummyChairData.sql"]),
+            estate: env("127.0.0.1", "isuumo", &["1_DummyEstateData.sql"]),
+        };
+        assert_eq!(
+            envs.init_plan(),
+            InitPlan {
+                chair: vec!["2_DummyChairData.sql"],
+                estate: vec!["0_Schema.sql", "1_DummyEstateData.sql"],
+                serial: true,
+            }
+        );
+    }
+
+    #[test]
+    fn separate_databases_each_run_the_schema_concurrently() {
+        let envs = MultiMySQLConnectionEnv {
+            chair: env("10.0.0.2", "isuumo", &["2_DummyChairData.sql"]),
+            estate: env("10.0.0.3", "isuumo", &["1_DummyEstateData.sql"]),
+        };
+        assert_eq!(
+            envs.init_plan(),
+            InitPlan {
+                chair: vec!["0_Schema.sql", "2_DummyChairData.sql"],
+                estate: vec!["0_Schema.sql", "1_DummyEstateData.sql"],
+                serial: false,
+            }
+        );
+    }
+
+    #[test]
+    fn multi_value_parameters_are_trimmed() {
+        assert_eq!(split_list("a, b,,c ,"), vec!["a", "b", "c"]);
+        assert!(split_list(" , ").is_empty());
+
+        let cond: RangeCondition = serde_json::from_str(
+            r#"{"prefix": "", "suffix": "", "ranges": [
+                {"id": 0, "min": -1, "max": 80}, {"id": 1, "min": 80, "max": -1}]}"#,
+        )
+        .unwrap();
+        assert_eq!(parse_range_ids(&cond, "widthRangeId", "1, 0").unwrap(), vec![0, 1]);
+        assert_eq!(parse_range_ids(&cond, "widthRangeId", "").unwrap(), Vec::<usize>::new());
+        assert!(parse_range_ids(&cond, "widthRangeId", "0, 2").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn extractor_failures_return_structured_errors() {
+        use actix_web::test;
+
+        async fn by_id(path: web::Path<(i64,)>) -> HttpResponse {
+            HttpResponse::Ok().body(path.0.to_string())
+        }
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(path_config())
+                .route("/chair/{id}", web::get().to(by_id)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/chair/abc").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["code"], "invalid_parameter");
+        assert_eq!(body["param"], "id");
     }
 }
@@ -0,0 +1,66 @@
+use crate::geometry::Polygon;
+use crate::Coordinate;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+// Nazotte results are ordered by popularity desc, id desc.
+type SortKey = (Reverse<i64>, Reverse<i64>);
+
+/// Locations of every estate, kept in result order for the nazotte search.
+#[derive(Debug, Default)]
+pub struct EstateLocationIndex {
+    locations: BTreeMap<SortKey, Coordinate>,
+}
+
+impl EstateLocationIndex {
+    /// Builds the index from `(id, latitude, longitude, popularity)` rows.
+    pub fn new(rows: Vec<(i64, f64, f64, i64)>) -> Self {
+        let mut index = Self::default();
+        for (id, latitude, longitude, popularity) in rows {
+            index.insert(id, popularity, Coordinate { latitude, longitude });
+        }
+        index
+    }
+
+    pub fn insert(&mut self, id: i64, popularity: i64, location: Coordinate) {
+        self.locations
+            .insert((Reverse(popularity), Reverse(id)), location);
+    }
+
+    /// Returns the ids of up to `limit` estates inside the polygon, most popular first.
+    pub fn search(&self, polygon: &Polygon, limit: usize) -> Vec<i64> {
+        self.locations
+            .iter()
+            .filter(|(_, location)| polygon.contains(location))
+            .map(|((_, Reverse(id)), _)| *id)
+            .take(limit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Coordinates;
+
+    #[test]
+    fn index_orders_by_popularity_then_id_and_applies_limit() {
+        let index = EstateLocationIndex::new(vec![
+            (1, 5.0, 5.0, 10),
+            (2, 5.0, 5.0, 30),
+            (3, 5.0, 5.0, 30),
+            (4, 50.0, 50.0, 100),
+            (5, 5.0, 5.0, 20),
+        ]);
+        let polygon = Coordinates {
+            coordinates: [(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0)]
+                .iter()
+                .map(|&(latitude, longitude)| Coordinate { latitude, longitude })
+                .collect(),
+        }
+        .to_polygon()
+        .unwrap();
+        assert_eq!(index.search(&polygon, 10), vec![3, 2, 5, 1]);
+        assert_eq!(index.search(&polygon, 2), vec![3, 2]);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::error::AppError;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use serde::Deserialize;
+use std::env;
+
+const DEFAULT_PER_PAGE: i64 = 25;
+const DEFAULT_MAX_PER_PAGE: i64 = 100;
+const DEFAULT_MAX_OFFSET: i64 = 10_000;
+
+/// A sort key: ascending order of the key is the order of the results.
+pub type SortKey = (i64, i64);
+
+/// Where a page starts: after skipping `n` results, or after the result with the given key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStart {
+    Offset(usize),
+    After(SortKey),
+}
+
+/// Limits applied to the paging parameters of every search.
+#[derive(Debug, Clone)]
+pub struct PaginationConfig {
+    /// `perPage` used when the request has none.
+    pub default_per_page: i64,
+    pub max_per_page: i64,
+    /// Largest offset `page * perPage` may reach; deeper pages must be walked with a cursor.
+    pub max_offset: i64,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        Self {
+            default_per_page: DEFAULT_PER_PAGE,
+            max_per_page: DEFAULT_MAX_PER_PAGE,
+            max_offset: DEFAULT_MAX_OFFSET,
+        }
+    }
+}
+
+impl PaginationConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: i64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v: &i64| v > 0)
+                .unwrap_or(default)
+        };
+        let max_per_page = var("PAGINATION_MAX_PER_PAGE", DEFAULT_MAX_PER_PAGE);
+        Self {
+            default_per_page: var("PAGINATION_DEFAULT_PER_PAGE", DEFAULT_PER_PAGE)
+                .min(max_per_page),
+            max_per_page,
+            max_offset: var("PAGINATION_MAX_OFFSET", DEFAULT_MAX_OFFSET),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PaginationParams {
+    page: Option<String>,
+    #[serde(rename = "perPage")]
+    per_page: Option<String>,
+    cursor: Option<String>,
+    #[serde(rename = "withCount")]
+    with_count: Option<String>,
+}
+
+/// The validated `page`, `perPage`, `cursor` and `withCount` query parameters of a search.
+///
+/// Limits come from the `PaginationConfig` in the app data, or its defaults when none is
+/// registered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pagination {
+    pub page: i64,
+    pub per_page: usize,
+    per_page_given: bool,
+    with_count_given: bool,
+    // Whether the request has any of the paging parameters.
+    requested: bool,
+    // The `nextCursor` of the previous page; takes precedence over `page`.
+    cursor: Option<String>,
+    // Checked in `start`, once the endpoint has settled on its page size.
+    max_offset: i64,
+    /// Whether the response includes the total count; `withCount=false` leaves it out, since
+    /// infinite scroll can do without it.
+    pub with_count: bool,
+}
+
+impl Pagination {
+    fn new(params: PaginationParams, config: &PaginationConfig) -> Result<Self, AppError> {
+        let given = parse_param("perPage", params.per_page)?;
+        let per_page = given.unwrap_or(config.default_per_page);
+        if !(1..=config.max_per_page).contains(&per_page) {
+            log::info!("perPage invalid, {} : Out of range", per_page);
+            return Err(AppError::invalid_parameter(
+                "perPage",
+                format!("must be between 1 and {}", config.max_per_page),
+            ));
+        }
+        let cursor = params.cursor.filter(|cursor| !cursor.is_empty());
+        let page_given = parse_param("page", params.page)?;
+        let page = page_given.unwrap_or(0);
+        if cursor.is_none() && page < 0 {
+            log::info!("page invalid, {} : Out of range", page);
+            return Err(page_out_of_range(config.max_offset));
+        }
+        let with_count_given = params.with_count.as_deref().is_some_and(|v| !v.is_empty());
+        let with_count = match params.with_count.as_deref() {
+            None | Some("") | Some("true") => true,
+            Some("false") => false,
+            Some(value) => {
+                log::info!("withCount invalid, {} : Not a boolean", value);
+                return Err(AppError::invalid_parameter(
+                    "withCount",
+                    "must be true or false",
+                ));
+            }
+        };
+        Ok(Self {
+            page,
+            per_page: per_page as usize,
+            per_page_given: given.is_some(),
+            with_count_given,
+            requested: given.is_some() || page_given.is_some() || cursor.is_some() || with_count_given,
+            cursor,
+            max_offset: config.max_offset,
+            with_count,
+        })
+    }
+
+    /// Uses `per_page` rather than the configured default when the request has no `perPage`,
+    /// for endpoints whose page size predates paging. It must not exceed the configured
+    /// `max_per_page`.
+    pub fn or_per_page(mut self, per_page: usize) -> Self {
+        if !self.per_page_given {
+            self.per_page = per_page;
+        }
+        self
+    }
+
+    /// Leaves out the count unless the request has `withCount=true`, for endpoints that had
+    /// no count before paging.
+    pub fn or_without_count(mut self) -> Self {
+        if !self.with_count_given {
+            self.with_count = false;
+        }
+        self
+    }
+
+    /// Whether the request has any paging parameter. Endpoints that predate paging only
+    /// add paging fields to their response when it does.
+    pub fn is_requested(&self) -> bool {
+        self.requested
+    }
+
+    /// Where the page starts in a search sorted by `sort`. A cursor must have been issued
+    /// for the same sort, since a key of one order means nothing in another; a page must
+    /// not start past the configured `max_offset` at the final page size.
+    pub fn start(&self, sort: &str) -> Result<PageStart, AppError> {
+        match &self.cursor {
+            Some(cursor) => decode_cursor(cursor, sort)
+                .map(PageStart::After)
+                .ok_or_else(|| {
+                    log::info!("cursor invalid, {} : Malformed or for another sort", cursor);
+                    AppError::invalid_parameter("cursor", "malformed or issued for another sort")
+                }),
+            None => {
+                let offset = self.page.saturating_mul(self.per_page as i64);
+                if offset > self.max_offset {
+                    log::info!("page invalid, {} : Out of range", self.page);
+                    return Err(page_out_of_range(self.max_offset));
+                }
+                Ok(PageStart::Offset(offset as usize))
+            }
+        }
+    }
+
+    /// The cursor to the page after one that ended with `last`, or `None` when the page was
+    /// not full and so nothing follows it.
+    pub fn next_cursor(&self, sort: &str, len: usize, last: Option<SortKey>) -> Option<String> {
+        if len < self.per_page {
+            return None;
+        }
+        last.map(|key| encode_cursor(sort, key))
+    }
+}
+
+fn page_out_of_range(max_offset: i64) -> AppError {
+    AppError::invalid_parameter(
+        "page",
+        format!(
+            "must not be negative nor reach past {} results; use cursor instead",
+            max_offset
+        ),
+    )
+}
+
+fn parse_param(param: &'static str, value: Option<String>) -> Result<Option<i64>, AppError> {
+    match value.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            log::info!("{} invalid, {} : Not an integer", param, value);
+            AppError::invalid_parameter(param, "must be an integer")
+        }),
+    }
+}
+
+impl FromRequest for Pagination {
+    type Error = AppError;
+    type Future = Ready<Result<Self, AppError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let default_config = PaginationConfig::default();
+        let config = req
+            .app_data::<web::Data<PaginationConfig>>()
+            .map(|config| config.get_ref())
+            .unwrap_or(&default_config);
+        ready(
+            web::Query::<PaginationParams>::from_query(req.query_string())
+                .map_err(|e| AppError::InvalidRequest(e.to_string()))
+                .and_then(|params| Pagination::new(params.into_inner(), config)),
+        )
+    }
+}
+
+// Cursors are the hex encoding of `sort:key0:key1`; opaque to clients and URL-safe.
+fn encode_cursor(sort: &str, (a, b): SortKey) -> String {
+    format!("{}:{}:{}", sort, a, b)
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_cursor(cursor: &str, sort: &str) -> Option<SortKey> {
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let text = String::from_utf8(bytes).ok()?;
+    let mut parts = text.split(':');
+    if parts.next()? != sort {
+        return None;
+    }
+    let key = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    match parts.next() {
+        Some(_) => None,
+        None => Some(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pagination(query: &str) -> Result<Pagination, AppError> {
+        let params = web::Query::<PaginationParams>::from_query(query).unwrap();
+        let config = PaginationConfig {
+            default_per_page: 20,
+            max_per_page: 50,
+            max_offset: 1000,
+        };
+        Pagination::new(params.into_inner(), &config)
+    }
+
+    fn param<T: std::fmt::Debug>(result: Result<T, AppError>) -> &'static str {
+        match result {
+            Err(AppError::InvalidParameter { param, .. }) => param,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip_for_their_sort_only() {
+        let cursor = encode_cursor("popularity", (-120, -7));
+        assert!(cursor.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(decode_cursor(&cursor, "popularity"), Some((-120, -7)));
+        assert_eq!(decode_cursor(&cursor, "price_asc"), None);
+        assert_eq!(decode_cursor("zz", "popularity"), None);
+        assert_eq!(decode_cursor(&cursor[1..], "popularity"), None);
+        assert_eq!(
+            decode_cursor(&encode_cursor("popularity:1", (2, 3)), "popularity"),
+            None
+        );
+    }
+
+    #[test]
+    fn applies_defaults() {
+        let paging = pagination("").unwrap();
+        assert_eq!(
+            (paging.page, paging.per_page, paging.with_count),
+            (0, 20, true)
+        );
+        assert_eq!(paging.start("popularity").unwrap(), PageStart::Offset(0));
+        let paging = pagination("page=2&perPage=25&other=1").unwrap();
+        assert_eq!(paging.start("popularity").unwrap(), PageStart::Offset(50));
+    }
+
+    #[test]
+    fn rejects_malformed_negative_or_huge_paging() {
+        assert_eq!(param(pagination("page=-1")), "page");
+        assert_eq!(param(pagination("page=x")), "page");
+        let start = |query| pagination(query).and_then(|paging| paging.start("popularity"));
+        assert_eq!(param(start("page=51")), "page");
+        assert_eq!(param(start("page=9223372036854775807")), "page");
+        assert_eq!(param(pagination("perPage=0")), "perPage");
+        assert_eq!(param(pagination("perPage=-1")), "perPage");
+        assert_eq!(param(pagination("perPage=51")), "perPage");
+        assert_eq!(param(pagination("perPage=1e3")), "perPage");
+        assert_eq!(param(pagination("withCount=no")), "withCount");
+        assert!(start("page=50").is_ok());
+        assert!(!pagination("withCount=false").unwrap().with_count);
+        assert_eq!(pagination("").unwrap().or_per_page(10).per_page, 10);
+        assert_eq!(pagination("perPage=5").unwrap().or_per_page(10).per_page, 5);
+    }
+
+    #[test]
+    fn bounds_the_offset_at_the_endpoint_page_size() {
+        let start = |query, per_page| {
+            pagination(query).and_then(|paging| paging.or_per_page(per_page).start("popularity"))
+        };
+        assert_eq!(start("page=100", 10).unwrap(), PageStart::Offset(1000));
+        assert_eq!(param(start("page=101", 10)), "page");
+        assert_eq!(param(start("page=60&perPage=20", 10)), "page");
+        assert_eq!(param(start("page=-1", 10)), "page");
+    }
+
+    #[test]
+    fn tells_whether_paging_was_requested() {
+        let paging = pagination("other=1").unwrap();
+        assert!(!paging.is_requested());
+        assert!(!paging.or_without_count().with_count);
+        for query in &["page=1", "perPage=5", "cursor=00", "withCount=false"] {
+            assert!(pagination(query).unwrap().is_requested(), "{}", query);
+        }
+        assert!(pagination("withCount=true").unwrap().or_without_count().with_count);
+        assert!(!pagination("page=1").unwrap().or_without_count().with_count);
+    }
+
+    #[test]
+    fn cursor_takes_precedence_over_page() {
+        let cursor = encode_cursor("newest", (-9, 0));
+        let paging = pagination(&format!("page=3000&perPage=10&cursor={}", cursor)).unwrap();
+        assert_eq!(paging.start("newest").unwrap(), PageStart::After((-9, 0)));
+        assert_eq!(param(paging.start("popularity")), "cursor");
+    }
+
+    #[test]
+    fn only_full_pages_have_a_next_cursor() {
+        let paging = pagination("perPage=2").unwrap();
+        assert!(paging.next_cursor("newest", 2, Some((-9, 0))).is_some());
+        assert_eq!(paging.next_cursor("newest", 1, Some((-9, 0))), None);
+    }
+}
@@ -0,0 +1,116 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use bytes::Bytes;
+use serde::Serialize;
+
+/// How long clients and the nginx tier may reuse a response before revalidating it.
+const MAX_AGE_SECS: u32 = 60;
+
+/// A JSON body rendered once, with a strong ETag derived from its bytes.
+#[derive(Debug, Clone)]
+pub struct RenderedJson {
+    json: Bytes,
+    etag: String,
+}
+
+impl RenderedJson {
+    pub fn new<T: Serialize>(value: &T) -> serde_json::Result<Self> {
+        let json = serde_json::to_vec(value)?;
+        let etag = format!("\"{:016x}\"", fnv1a(&json));
+        Ok(Self {
+            json: json.into(),
+            etag,
+        })
+    }
+
+    /// Responds with the body, or with 304 when the request's `If-None-Match` lists our ETag.
+    pub fn respond(&self, req: &HttpRequest) -> HttpResponse {
+        let not_modified = req
+            .headers()
+            .get_all(header::IF_NONE_MATCH)
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| etag_matches(value, &self.etag));
+        let mut response = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        response.header(header::ETAG, self.etag.as_str()).header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", MAX_AGE_SECS),
+        );
+        if not_modified {
+            response.finish()
+        } else {
+            response
+                .content_type("application/json")
+                .body(self.json.clone())
+        }
+    }
+}
+
+// FNV-1a is stable across builds and processes, so every app server hands out the same ETag.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// `If-None-Match` uses the weak comparison, so a `W/` prefix on the client's tag is ignored.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn etag_is_strong_and_depends_on_the_body() {
+        let a = RenderedJson::new(&vec![1, 2]).unwrap();
+        let b = RenderedJson::new(&vec![1, 2]).unwrap();
+        let c = RenderedJson::new(&vec![2, 1]).unwrap();
+        assert_eq!(a.etag, b.etag);
+        assert_ne!(a.etag, c.etag);
+        assert!(a.etag.starts_with('"') && a.etag.ends_with('"'));
+    }
+
+    #[test]
+    fn if_none_match_lists_and_wildcards() {
+        let etag = "\"abc\"";
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("\"x\", W/\"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"abcd\"", etag));
+        assert!(!etag_matches("abc", etag));
+    }
+
+    #[test]
+    fn respond_returns_304_for_a_matching_etag() {
+        let rendered = RenderedJson::new(&vec![1]).unwrap();
+
+        let response = rendered.respond(&TestRequest::default().to_http_request());
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::ETAG).unwrap(),
+            rendered.etag.as_str()
+        );
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+
+        let request = TestRequest::default()
+            .header(header::IF_NONE_MATCH, rendered.etag.as_str())
+            .to_http_request();
+        let response = rendered.respond(&request);
+        assert_eq!(response.status(), 304);
+        assert_eq!(
+            response.headers().get(header::ETAG).unwrap(),
+            rendered.etag.as_str()
+        );
+    }
+}
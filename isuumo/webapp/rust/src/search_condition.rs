@@ -0,0 +1,534 @@
+use crate::chair_index::ChairIndex;
+use crate::features::{MAX_FEATURES, MAX_STORED_FEATURES};
+use crate::rendered_json::RenderedJson;
+use crate::{AppCache, ChairSearchCondition, EstateSearchCondition, ListCondition, RangeCondition};
+use actix_web::web;
+use arc_swap::ArcSwap;
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 2000;
+
+/// One consistent version of both fixtures together with their rendered JSON.
+#[derive(Debug)]
+pub struct SearchConditions {
+    pub chair: Arc<ChairSearchCondition>,
+    pub estate: Arc<EstateSearchCondition>,
+    pub chair_json: RenderedJson,
+    pub estate_json: RenderedJson,
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_reader(io::BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+impl SearchConditions {
+    fn load(chair_path: &Path, estate_path: &Path) -> Result<Self, String> {
+        let chair: ChairSearchCondition = read_json(chair_path)?;
+        chair
+            .validate()
+            .map_err(|e| format!("{}: {}", chair_path.display(), e))?;
+        let estate: EstateSearchCondition = read_json(estate_path)?;
+        estate
+            .validate()
+            .map_err(|e| format!("{}: {}", estate_path.display(), e))?;
+        Ok(Self {
+            chair_json: RenderedJson::new(&chair).map_err(|e| e.to_string())?,
+            estate_json: RenderedJson::new(&estate).map_err(|e| e.to_string())?,
+            chair: Arc::new(chair),
+            estate: Arc::new(estate),
+        })
+    }
+}
+
+impl ChairSearchCondition {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_ranges("width", &self.width)?;
+        validate_ranges("height", &self.height)?;
+        validate_ranges("depth", &self.depth)?;
+        validate_ranges("price", &self.price)?;
+        validate_list("color", &self.color)?;
+        validate_list("feature", &self.feature)?;
+        validate_list("kind", &self.kind)
+    }
+}
+
+impl EstateSearchCondition {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_ranges("doorWidth", &self.door_width)?;
+        validate_ranges("doorHeight", &self.door_height)?;
+        validate_ranges("rent", &self.rent)?;
+        validate_list("feature", &self.feature)?;
+        if self.feature.list.len() > MAX_STORED_FEATURES {
+            return Err(format!("feature: more than {} items", MAX_STORED_FEATURES));
+        }
+        Ok(())
+    }
+
+    /// Stored feature masks refer to positions in the feature list, so a new version may
+    /// only add features after the existing ones.
+    pub fn validate_update(&self, current: &EstateSearchCondition) -> Result<(), String> {
+        if self.feature.list.starts_with(&current.feature.list) {
+            Ok(())
+        } else {
+            Err("feature: existing items may not be removed or reordered, only appended".to_owned())
+        }
+    }
+}
+
+/// Checks that range ids are unique and that the ranges are ascending and disjoint, with
+/// only the first range open below and only the last one open above.
+fn validate_ranges(name: &str, cond: &RangeCondition) -> Result<(), String> {
+    if cond.ranges.is_empty() {
+        return Err(format!("{}: no ranges", name));
+    }
+    let last = cond.ranges.len() - 1;
+    let mut ids = HashSet::with_capacity(cond.ranges.len());
+    for (i, range) in cond.ranges.iter().enumerate() {
+        if range.id < 0 || !ids.insert(range.id) {
+            return Err(format!(
+                "{}: invalid or duplicate range id {}",
+                name, range.id
+            ));
+        }
+        if (range.min.is_none() && i != 0) || (range.max.is_none() && i != last) {
+            return Err(format!(
+                "{}: only the first range may be open below and only the last one above, not range {}",
+                name, range.id
+            ));
+        }
+        if range.min.unwrap_or(0) < 0 || range.max.unwrap_or(0) < 0 {
+            return Err(format!("{}: range {} has a negative bound", name, range.id));
+        }
+        if let (Some(min), Some(max)) = (range.min, range.max) {
+            if min >= max {
+                return Err(format!("{}: range {} is empty", name, range.id));
+            }
+        }
+        if i > 0 && cond.ranges[i - 1].max > range.min {
+            return Err(format!(
+                "{}: range {} overlaps or precedes range {}",
+                name,
+                range.id,
+                cond.ranges[i - 1].id
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_list(name: &str, cond: &ListCondition) -> Result<(), String> {
+    if cond.list.len() > MAX_FEATURES {
+        return Err(format!("{}: more than {} items", name, MAX_FEATURES));
+    }
+    let mut seen = HashSet::with_capacity(cond.list.len());
+    for item in &cond.list {
+        // Features are sent and stored comma-separated.
+        if item.is_empty() || item.contains(',') {
+            return Err(format!("{}: invalid item {:?}", name, item));
+        }
+        if !seen.insert(item) {
+            return Err(format!("{}: duplicate item {}", name, item));
+        }
+    }
+    Ok(())
+}
+
+/// The current search conditions, replaced as a whole when the fixtures are reloaded.
+///
+/// Handlers `load` once per request so a reload never mixes versions within one search.
+#[derive(Debug)]
+pub struct SearchConditionStore {
+    chair_path: PathBuf,
+    estate_path: PathBuf,
+    current: ArcSwap<SearchConditions>,
+}
+
+impl SearchConditionStore {
+    pub fn from_env() -> Result<Self, String> {
+        let chair_path: PathBuf = env::var("CHAIR_CONDITION_PATH")
+            .unwrap_or_else(|_| "../fixture/chair_condition.json".to_owned())
+            .into();
+        let estate_path: PathBuf = env::var("ESTATE_CONDITION_PATH")
+            .unwrap_or_else(|_| "../fixture/estate_condition.json".to_owned())
+            .into();
+        Self::open(chair_path, estate_path)
+    }
+
+    fn open(chair_path: PathBuf, estate_path: PathBuf) -> Result<Self, String> {
+        let conditions = SearchConditions::load(&chair_path, &estate_path)?;
+        Ok(Self {
+            chair_path,
+            estate_path,
+            current: ArcSwap::from_pointee(conditions),
+        })
+    }
+
+    pub fn load(&self) -> Arc<SearchConditions> {
+        self.current.load_full()
+    }
+
+    /// Re-reads both fixtures. Invalid fixtures are rejected and the current version is kept.
+    pub fn reload(&self, cache: &AppCache) -> Result<(), String> {
+        let conditions = SearchConditions::load(&self.chair_path, &self.estate_path)?;
+        conditions
+            .estate
+            .validate_update(&self.load().estate)
+            .map_err(|e| format!("{}: {}", self.estate_path.display(), e))?;
+        // The chair index buckets by the chair ranges, so it is rebuilt before publishing.
+        ChairIndex::rebuild(&cache.chair_index, conditions.chair.clone());
+        self.current.store(Arc::new(conditions));
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.chair_path)?, modified(&self.estate_path)?))
+    }
+}
+
+/// Reloads the fixtures on SIGHUP and, unless `CONDITION_POLL_INTERVAL_MS` is 0,
+/// whenever their modification time changes.
+pub fn spawn_reloader(
+    store: web::Data<SearchConditionStore>,
+    cache: web::Data<AppCache>,
+) -> io::Result<()> {
+    let (trigger, triggered) = mpsc::channel();
+    let mut hangup = signal(SignalKind::hangup())?;
+    actix_rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading search conditions");
+            if trigger.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let poll_interval = env::var("CONDITION_POLL_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(DEFAULT_POLL_INTERVAL_MS);
+    thread::Builder::new()
+        .name("condition-reloader".to_owned())
+        .spawn(move || {
+            let mut last_modified = store.modified();
+            loop {
+                let signaled = if poll_interval == 0 {
+                    match triggered.recv() {
+                        Ok(()) => true,
+                        Err(_) => return,
+                    }
+                } else {
+                    match triggered.recv_timeout(Duration::from_millis(poll_interval)) {
+                        Ok(()) => true,
+                        Err(RecvTimeoutError::Timeout) => false,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            thread::sleep(Duration::from_millis(poll_interval));
+                            false
+                        }
+                    }
+                };
+                let modified = store.modified();
+                if !signaled && modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match store.reload(&cache) {
+                    Ok(()) => log::info!("search conditions reloaded"),
+                    Err(e) => log::error!(
+                        "search conditions not reloaded, keeping the current ones : {}",
+                        e
+                    ),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chair_index::ChairQuery;
+    use crate::count_cache::CountCache;
+    use crate::nazotte::EstateLocationIndex;
+    use crate::pagination::PageStart;
+    use crate::snapshot::SnapshotCell;
+    use crate::{EstateListResponse, Range};
+    use std::sync::RwLock;
+
+    fn ranges(bounds: &[(i64, i64, i64)]) -> RangeCondition {
+        RangeCondition {
+            prefix: String::new(),
+            suffix: String::new(),
+            ranges: bounds
+                .iter()
+                .map(|&(id, min, max)| Range {
+                    id,
+                    min: Some(min).filter(|&v| v != -1),
+                    max: Some(max).filter(|&v| v != -1),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_the_fixture_layout() {
+        let cond = ranges(&[(0, -1, 80), (1, 80, 110), (2, 110, 150), (3, 150, -1)]);
+        assert_eq!(validate_ranges("width", &cond), Ok(()));
+        // Gaps between ranges are allowed.
+        assert_eq!(
+            validate_ranges("width", &ranges(&[(0, 0, 10), (1, 20, 30)])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_unsorted_overlapping_or_empty_ranges() {
+        assert!(validate_ranges("w", &ranges(&[])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 80, 110), (1, -1, 80)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 0, 100), (1, 90, 200)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 100, 100)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 200, 100)])).is_err());
+    }
+
+    #[test]
+    fn ids_need_not_match_positions() {
+        assert_eq!(
+            validate_ranges("w", &ranges(&[(1, -1, 80), (0, 80, -1)])),
+            Ok(())
+        );
+        assert_eq!(
+            validate_ranges("w", &ranges(&[(0, -1, 80), (2, 80, -1)])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_ids_and_misplaced_open_bounds() {
+        assert!(validate_ranges("w", &ranges(&[(0, -1, 80), (0, 80, -1)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(-2, -1, 80)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 0, -1), (1, 80, 110)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, 0, 80), (1, -1, 110)])).is_err());
+        assert!(validate_ranges("w", &ranges(&[(0, -5, 80)])).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_or_malformed_list_items() {
+        let list = |items: &[&str]| ListCondition {
+            list: items.iter().map(|s| s.to_string()).collect(),
+        };
+        assert_eq!(validate_list("kind", &list(&["a", "b"])), Ok(()));
+        assert!(validate_list("kind", &list(&["a", "a"])).is_err());
+        assert!(validate_list("kind", &list(&["a,b"])).is_err());
+        assert!(validate_list("kind", &list(&[""])).is_err());
+    }
+
+    #[test]
+    fn estate_features_may_only_be_appended() {
+        let estate = |items: &[&str]| {
+            let mut cond: EstateSearchCondition =
+                read_json(Path::new("../fixture/estate_condition.json")).unwrap();
+            cond.feature.list = items.iter().map(|s| s.to_string()).collect();
+            cond
+        };
+        let current = estate(&["a", "b"]);
+        assert_eq!(estate(&["a", "b", "c"]).validate_update(&current), Ok(()));
+        assert!(estate(&["b", "a"]).validate_update(&current).is_err());
+        assert!(estate(&["a"]).validate_update(&current).is_err());
+        let many: Vec<String> = (0..65).map(|i| i.to_string()).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        assert!(estate(&many).validate().is_err());
+    }
+
+    #[test]
+    fn open_bounds_round_trip_as_minus_one() {
+        let range: Range = serde_json::from_str(r#"{"id": 3, "min": 150, "max": -1}"#).unwrap();
+        assert_eq!((range.min, range.max), (Some(150), None));
+        assert!(range.contains(150) && range.contains(i64::MAX) && !range.contains(149));
+        assert_eq!(
+            serde_json::to_string(&range).unwrap(),
+            r#"{"id":3,"min":150,"max":-1}"#
+        );
+    }
+
+    // Copies of the bundled fixtures under a name unique to the test, edited by `write`.
+    struct Fixtures {
+        chair: PathBuf,
+        estate: PathBuf,
+    }
+
+    impl Fixtures {
+        fn new(test: &str) -> Self {
+            let path = |name: &str| {
+                env::temp_dir().join(format!("{}-{}-{}.json", test, std::process::id(), name))
+            };
+            let fixtures = Self {
+                chair: path("chair"),
+                estate: path("estate"),
+            };
+            fixtures.write(|_| {}, |_| {});
+            fixtures
+        }
+
+        fn write(
+            &self,
+            chair: impl FnOnce(&mut serde_json::Value),
+            estate: impl FnOnce(&mut serde_json::Value),
+        ) {
+            for (path, bundled, edit) in [
+                (&self.chair, "chair", Box::new(chair) as Box<dyn FnOnce(&mut _)>),
+                (&self.estate, "estate", Box::new(estate)),
+            ] {
+                let bundled = format!("../fixture/{}_condition.json", bundled);
+                let mut json: serde_json::Value = read_json(Path::new(&bundled)).unwrap();
+                edit(&mut json);
+                fs::write(path, json.to_string()).unwrap();
+            }
+        }
+
+        fn store(&self) -> SearchConditionStore {
+            SearchConditionStore::open(self.chair.clone(), self.estate.clone()).unwrap()
+        }
+    }
+
+    impl Drop for Fixtures {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.chair);
+            let _ = fs::remove_file(&self.estate);
+        }
+    }
+
+    fn cache(store: &SearchConditionStore, prices: &[i64]) -> AppCache {
+        let chairs = prices
+            .iter()
+            .enumerate()
+            .map(|(i, &price)| crate::Chair {
+                id: i as i64 + 1,
+                name: String::new(),
+                description: String::new(),
+                thumbnail: String::new(),
+                price,
+                height: 100,
+                width: 100,
+                depth: 100,
+                color: String::new(),
+                features: String::new(),
+                kind: String::new(),
+                popularity: 0,
+                stock: 1,
+            })
+            .collect();
+        AppCache {
+            low_priced_estates: SnapshotCell::new(EstateListResponse { estates: vec![] }),
+            chair_index: RwLock::new(ChairIndex::new(store.load().chair.clone(), chairs)),
+            estate_locations: RwLock::new(EstateLocationIndex::default()),
+            estate_counts: CountCache::default(),
+        }
+    }
+
+    // Ids of the indexed chairs in the price range at `position`.
+    fn in_price_range(cache: &AppCache, position: usize) -> Vec<i64> {
+        let query = ChairQuery {
+            price: vec![position],
+            ..ChairQuery::default()
+        };
+        let (_, chairs) = cache
+            .chair_index
+            .read()
+            .unwrap()
+            .search(&query, PageStart::Offset(0), 10);
+        let mut ids: Vec<i64> = chairs.iter().map(|chair| chair.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn chair_etag(store: &SearchConditionStore) -> String {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let response = store.load().chair_json.respond(&req);
+        response.headers()
+            .get(actix_web::http::header::ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn invalid_fixtures_keep_the_current_conditions_and_index() {
+        let fixtures = Fixtures::new("reload-invalid");
+        let store = fixtures.store();
+        let cache = cache(&store, &[1000, 4000]);
+        let current = store.load();
+        fixtures.write(|chair| chair["price"]["ranges"] = serde_json::json!([]), |_| {});
+        assert!(store.reload(&cache).is_err());
+        assert!(Arc::ptr_eq(&store.load(), &current));
+        assert!(Arc::ptr_eq(
+            cache.chair_index.read().unwrap().condition(),
+            &current.chair
+        ));
+        assert_eq!(in_price_range(&cache, 0), vec![1]);
+    }
+
+    #[test]
+    fn reloading_rebuckets_the_chair_index_and_changes_the_etag() {
+        let fixtures = Fixtures::new("reload-valid");
+        let store = fixtures.store();
+        let cache = cache(&store, &[1000, 4000, 5500]);
+        let etag = chair_etag(&store);
+        assert_eq!(in_price_range(&cache, 0), vec![1]);
+        assert_eq!(in_price_range(&cache, 1), vec![2, 3]);
+        fixtures.write(
+            |chair| {
+                chair["price"]["ranges"][0]["max"] = 5000.into();
+                chair["price"]["ranges"][1]["min"] = 5000.into();
+            },
+            |_| {},
+        );
+        assert_eq!(store.reload(&cache), Ok(()));
+        assert_eq!(in_price_range(&cache, 0), vec![1, 2]);
+        assert_eq!(in_price_range(&cache, 1), vec![3]);
+        assert!(Arc::ptr_eq(
+            cache.chair_index.read().unwrap().condition(),
+            &store.load().chair
+        ));
+        assert_ne!(chair_etag(&store), etag);
+    }
+
+    #[test]
+    fn reordered_estate_features_are_rejected_on_reload() {
+        let fixtures = Fixtures::new("reload-reordered");
+        let store = fixtures.store();
+        let cache = cache(&store, &[]);
+        let current = store.load();
+        fixtures.write(
+            |_| {},
+            |estate| {
+                let list = estate["feature"]["list"].as_array_mut().unwrap();
+                list.swap(0, 1);
+            },
+        );
+        let error = store.reload(&cache).unwrap_err();
+        assert!(error.contains("only appended"), "{}", error);
+        assert!(Arc::ptr_eq(&store.load(), &current));
+    }
+
+    #[test]
+    fn bundled_fixtures_are_valid() {
+        let conditions = SearchConditions::load(
+            Path::new("../fixture/chair_condition.json"),
+            Path::new("../fixture/estate_condition.json"),
+        );
+        assert!(conditions.is_ok(), "{:?}", conditions.err());
+    }
+}
@@ -0,0 +1,92 @@
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// An immutable cached value together with its JSON rendering.
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    pub value: T,
+    pub json: Bytes,
+}
+
+impl<T: Serialize> Snapshot<T> {
+    fn new(value: T) -> Self {
+        let json = serde_json::to_vec(&value).expect("cached value must serialize to JSON");
+        Self {
+            value,
+            json: json.into(),
+        }
+    }
+}
+
+/// Holds the current `Snapshot` and replaces it atomically.
+///
+/// Readers take an `Arc` to whatever snapshot is current and never wait on writers;
+/// the JSON is rendered once per update instead of once per request.
+pub struct SnapshotCell<T> {
+    current: ArcSwap<Snapshot<T>>,
+}
+
+impl<T: Serialize> SnapshotCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(Snapshot::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Snapshot<T>> {
+        self.current.load_full()
+    }
+
+    pub fn store(&self, value: T) {
+        self.current.store(Arc::new(Snapshot::new(value)));
+    }
+
+    /// Publishes `f(current)`. `f` is re-run if another writer published in the meantime,
+    /// so concurrent updates are never lost.
+    pub fn update(&self, mut f: impl FnMut(&T) -> T) {
+        self.current
+            .rcu(|current| Arc::new(Snapshot::new(f(&current.value))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn json_follows_the_published_value() {
+        let cell = SnapshotCell::new(vec![1]);
+        let before = cell.load();
+        cell.store(vec![1, 2]);
+        assert_eq!(&before.json[..], b"[1]");
+        assert_eq!(&cell.load().json[..], b"[1,2]");
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let cell = Arc::new(SnapshotCell::new(Vec::new()));
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    for j in 0..50 {
+                        cell.update(|v| {
+                            let mut v = v.clone();
+                            v.push(i * 50 + j);
+                            v
+                        });
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let mut values = cell.load().value.clone();
+        values.sort_unstable();
+        assert_eq!(values, (0..400).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,133 @@
+use crate::pagination::SortKey;
+use crate::{Chair, Estate};
+
+/// Orders accepted by the `sort` parameter of chair search. Every order ends with an id
+/// tiebreak in the same direction, so paging is deterministic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChairSort {
+    #[default]
+    Popularity,
+    PriceAsc,
+    PriceDesc,
+    Newest,
+    SizeAsc,
+    SizeDesc,
+}
+
+impl ChairSort {
+    pub const NAMES: &'static str =
+        "popularity, price_asc, price_desc, newest, size_asc, size_desc";
+
+    /// Parses the `sort` parameter; an empty value means the default order.
+    pub fn from_param(param: &str) -> Option<Self> {
+        Some(match param {
+            "" | "popularity" => ChairSort::Popularity,
+            "price_asc" => ChairSort::PriceAsc,
+            "price_desc" => ChairSort::PriceDesc,
+            "newest" => ChairSort::Newest,
+            "size_asc" => ChairSort::SizeAsc,
+            "size_desc" => ChairSort::SizeDesc,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChairSort::Popularity => "popularity",
+            ChairSort::PriceAsc => "price_asc",
+            ChairSort::PriceDesc => "price_desc",
+            ChairSort::Newest => "newest",
+            ChairSort::SizeAsc => "size_asc",
+            ChairSort::SizeDesc => "size_desc",
+        }
+    }
+
+    /// A key whose ascending order is this sort order.
+    pub fn key(self, chair: &Chair) -> SortKey {
+        match self {
+            ChairSort::Popularity => (-chair.popularity, -chair.id),
+            ChairSort::PriceAsc => (chair.price, chair.id),
+            ChairSort::PriceDesc => (-chair.price, -chair.id),
+            ChairSort::Newest => (-chair.id, 0),
+            ChairSort::SizeAsc => (chair_size(chair), chair.id),
+            ChairSort::SizeDesc => (-chair_size(chair), -chair.id),
+        }
+    }
+}
+
+/// Chairs are sized by volume.
+pub fn chair_size(chair: &Chair) -> i64 {
+    chair
+        .height
+        .saturating_mul(chair.width)
+        .saturating_mul(chair.depth)
+}
+
+/// Orders accepted by the `sort` parameter of estate search, each backed by an index:
+/// `idx_sort1 (popularity, id)`, `idx_sort2 (rent, id)` or the primary key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EstateSort {
+    #[default]
+    Popularity,
+    RentAsc,
+    RentDesc,
+    Newest,
+}
+
+impl EstateSort {
+    pub const NAMES: &'static str = "popularity, rent_asc, rent_desc, newest";
+
+    /// Parses the `sort` parameter; an empty value means the default order.
+    pub fn from_param(param: &str) -> Option<Self> {
+        Some(match param {
+            "" | "popularity" => EstateSort::Popularity,
+            "rent_asc" => EstateSort::RentAsc,
+            "rent_desc" => EstateSort::RentDesc,
+            "newest" => EstateSort::Newest,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EstateSort::Popularity => "popularity",
+            EstateSort::RentAsc => "rent_asc",
+            EstateSort::RentDesc => "rent_desc",
+            EstateSort::Newest => "newest",
+        }
+    }
+
+    /// The last estate's position in this order, as stored in a cursor.
+    pub fn key(self, estate: &Estate) -> SortKey {
+        match self {
+            EstateSort::Popularity => (estate.popularity, estate.id),
+            EstateSort::RentAsc | EstateSort::RentDesc => (estate.rent, estate.id),
+            EstateSort::Newest => (estate.id, 0),
+        }
+    }
+
+    /// A condition selecting the estates that come after `key` in this order. It is spelled
+    /// out rather than as a row comparison so MySQL can range-scan the sort index.
+    pub fn seek(self, (a, b): SortKey, params: &mut Vec<mysql::Value>) -> String {
+        let (column, op) = match self {
+            EstateSort::Popularity => ("popularity", "<"),
+            EstateSort::RentAsc => ("rent", ">"),
+            EstateSort::RentDesc => ("rent", "<"),
+            EstateSort::Newest => {
+                params.push(a.into());
+                return "id < ?".to_owned();
+            }
+        };
+        params.extend(vec![a.into(), a.into(), b.into()]);
+        format!("({0} {1} ? or ({0} = ? and id {1} ?))", column, op)
+    }
+
+    pub fn order_by(self) -> &'static str {
+        match self {
+            EstateSort::Popularity => "popularity desc, id desc",
+            EstateSort::RentAsc => "rent asc, id asc",
+            EstateSort::RentDesc => "rent desc, id desc",
+            EstateSort::Newest => "id desc",
+        }
+    }
+}
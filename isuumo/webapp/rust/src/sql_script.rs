@@ -0,0 +1,427 @@
+use mysql::prelude::*;
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::Instant;
+
+/// Emit a progress log line every this many statements.
+const PROGRESS_INTERVAL: u64 = 10_000;
+/// A batch is sent once it holds this many bytes of SQL or this many statements.
+const BATCH_BYTES: usize = 1024 * 1024;
+const BATCH_STATEMENTS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    // Inside a quoted string, identifier or comment opened by the given character.
+    Quoted(char),
+    Escaped(char),
+    BlockComment,
+}
+
+/// Splits a SQL script into statements on `;`, ignoring separators inside strings,
+/// quoted identifiers and comments.
+///
+/// Input is fed line by line so only the statement being assembled is held in memory.
+/// `--` and `#` comments are dropped; `/* */` comments are kept since `/*! */` carries
+/// version-conditional SQL.
+#[derive(Debug)]
+pub struct StatementSplitter {
+    state: State,
+    current: String,
+    start_line: usize,
+    line: usize,
+}
+
+impl Default for StatementSplitter {
+    fn default() -> Self {
+        Self {
+            state: State::Normal,
+            current: String::new(),
+            start_line: 1,
+            line: 0,
+        }
+    }
+}
+
+/// A complete statement and the line it starts on.
+#[derive(Debug, PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub sql: String,
+}
+
+impl StatementSplitter {
+    /// Feeds one line (including its newline, if any) and appends every statement it completes.
+    pub fn push_line(&mut self, line: &str, out: &mut Vec<Statement>) {
+        self.line += 1;
+        let mut chars = line.char_indices().peekable();
+        let mut comment = false;
+        while let Some((i, c)) = chars.next() {
+            match self.state {
+                State::Normal => match c {
+                    ';' => self.finish_statement(out),
+                    '\'' | '"' | '`' => {
+                        self.state = State::Quoted(c);
+                        self.push_char(c);
+                    }
+                    '-' if is_line_comment(&line[i..]) => {
+                        comment = true;
+                        break;
+                    }
+                    '#' => {
+                        comment = true;
+                        break;
+                    }
+                    '/' if chars.peek().map(|&(_, c)| c) == Some('*') => {
+                        chars.next();
+                        self.state = State::BlockComment;
+                        self.push_char('/');
+                        self.push_char('*');
+                    }
+                    _ => self.push_char(c),
+                },
+                State::Quoted(quote) => {
+                    self.push_char(c);
+                    if c == '\\' && quote != '`' {
+                        self.state = State::Escaped(quote);
+                    } else if c == quote {
+                        // A doubled quote is an escaped quote; the next character reopens the string.
+                        self.state = State::Normal;
+                    }
+                }
+                State::Escaped(quote) => {
+                    self.push_char(c);
+                    self.state = State::Quoted(quote);
+                }
+                State::BlockComment => {
+                    self.push_char(c);
+                    if c == '*' && chars.peek().map(|&(_, c)| c) == Some('/') {
+                        chars.next();
+                        self.push_char('/');
+                        self.state = State::Normal;
+                    }
+                }
+            }
+        }
+        if comment {
+            // The dropped comment took the line's newline with it.
+            self.push_char('\n');
+        }
+    }
+
+    /// Returns the trailing statement that was not terminated by `;`, if any.
+    pub fn finish(mut self) -> Option<Statement> {
+        let mut out = Vec::with_capacity(1);
+        self.finish_statement(&mut out);
+        out.pop()
+    }
+
+    /// Whether the input so far ends inside a string or comment.
+    pub fn is_unterminated(&self) -> bool {
+        self.state != State::Normal
+    }
+
+    fn push_char(&mut self, c: char) {
+        if self.current.is_empty() {
+            if c.is_whitespace() {
+                return;
+            }
+            self.start_line = self.line;
+        }
+        self.current.push(c);
+    }
+
+    fn finish_statement(&mut self, out: &mut Vec<Statement>) {
+        let sql = std::mem::take(&mut self.current);
+        let trimmed = sql.trim_end();
+        if !trimmed.is_empty() {
+            out.push(Statement {
+                line: self.start_line,
+                sql: trimmed.to_owned(),
+            });
+        }
+    }
+}
+
+// MySQL only treats `--` as a comment when it is followed by whitespace or the end of line.
+fn is_line_comment(rest: &str) -> bool {
+    rest.starts_with("--") && rest[2..].chars().next().is_none_or(char::is_whitespace)
+}
+
+/// Outcome of running one SQL file, reported back from `/initialize`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptReport {
+    pub file: String,
+    pub statements: u64,
+    pub batches: u64,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Failure while running a SQL file. Statements are sent in batches, so the failing
+/// statement is somewhere between `first_line` and `last_line`.
+#[derive(Debug)]
+pub struct ScriptError {
+    pub file: String,
+    pub first_line: usize,
+    pub last_line: usize,
+    pub statements: u64,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.first_line == 0 {
+            write!(f, "{}: ", self.file)?;
+        } else if self.first_line == self.last_line {
+            write!(f, "{}:{}: ", self.file, self.first_line)?;
+        } else {
+            write!(f, "{}:{}-{}: ", self.file, self.first_line, self.last_line)?;
+        }
+        write!(
+            f,
+            "{} ({} statements executed before the failure)",
+            self.message, self.statements
+        )
+    }
+}
+
+// Statements are joined and sent as one multi-statement query to save round trips.
+#[derive(Debug, Default)]
+struct Batch {
+    sql: String,
+    statements: u64,
+    first_line: usize,
+    last_line: usize,
+}
+
+impl Batch {
+    fn push(&mut self, statement: Statement) {
+        if self.statements == 0 {
+            self.first_line = statement.line;
+        } else {
+            self.sql.push_str(";\n");
+        }
+        self.last_line = statement.line;
+        self.sql.push_str(&statement.sql);
+        self.statements += 1;
+    }
+
+    fn is_full(&self) -> bool {
+        self.sql.len() >= BATCH_BYTES || self.statements >= BATCH_STATEMENTS
+    }
+}
+
+struct ScriptRunner<'a, C: Queryable> {
+    conn: &'a mut C,
+    report: ScriptReport,
+    batch: Batch,
+}
+
+impl<C: Queryable> ScriptRunner<'_, C> {
+    fn error(&self, first_line: usize, last_line: usize, message: String) -> ScriptError {
+        ScriptError {
+            file: self.report.file.clone(),
+            first_line,
+            last_line,
+            statements: self.report.statements,
+            message,
+        }
+    }
+
+    /// Sends the pending batch and walks every result set it produces.
+    ///
+    /// MySQL stops a multi-statement query at the first failing statement, but the
+    /// client only reports that failure while reading the failing statement's result
+    /// set: `query_drop` returns the first statement's outcome and dropping the result
+    /// discards the rest. Each set is therefore read here, and the statements that ran
+    /// before the failure are counted.
+    fn flush(&mut self) -> Result<(), ScriptError> {
+        if self.batch.statements == 0 {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let mut completed = 0;
+        let result = self.conn.query_iter(&batch.sql).and_then(|mut result| {
+            while let Some(set) = result.next_set() {
+                for row in set? {
+                    row?;
+                }
+                completed += 1;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            self.report.statements += completed;
+            return Err(self.error(batch.first_line, batch.last_line, e.to_string()));
+        }
+        let before = self.report.statements;
+        self.report.statements += batch.statements;
+        self.report.batches += 1;
+        if before / PROGRESS_INTERVAL != self.report.statements / PROGRESS_INTERVAL {
+            log::info!(
+                "{}: {} statements, {} bytes so far",
+                self.report.file,
+                self.report.statements,
+                self.report.bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Streams `path` through `conn` in batches of complete statements.
+///
+/// Memory use is bounded by the batch size plus the longest single statement.
+pub fn run_script<C: Queryable>(conn: &mut C, path: &Path) -> Result<ScriptReport, ScriptError> {
+    let started = Instant::now();
+    let mut runner = ScriptRunner {
+        conn,
+        report: ScriptReport {
+            file: path.display().to_string(),
+            statements: 0,
+            batches: 0,
+            bytes: 0,
+            elapsed_ms: 0,
+        },
+        batch: Batch::default(),
+    };
+
+    let file = File::open(path).map_err(|e| runner.error(0, 0, e.to_string()))?;
+    let mut reader = BufReader::new(file);
+    let mut splitter = StatementSplitter::default();
+    let mut line = String::new();
+    let mut ready = Vec::new();
+    loop {
+        line.clear();
+        let read = read_line(&mut reader, &mut line).map_err(|e| {
+            let line = splitter.line + 1;
+            runner.error(line, line, e.to_string())
+        })?;
+        if read == 0 {
+            break;
+        }
+        runner.report.bytes += read as u64;
+        splitter.push_line(&line, &mut ready);
+        for statement in ready.drain(..) {
+            runner.batch.push(statement);
+            if runner.batch.is_full() {
+                runner.flush()?;
+            }
+        }
+    }
+    if splitter.is_unterminated() {
+        let line = splitter.start_line;
+        return Err(runner.error(
+            line,
+            line,
+            "unterminated string or comment at end of file".to_owned(),
+        ));
+    }
+    if let Some(statement) = splitter.finish() {
+        runner.batch.push(statement);
+    }
+    runner.flush()?;
+
+    let mut report = runner.report;
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    log::info!(
+        "{}: {} statements in {} batches, {} bytes in {} ms",
+        report.file,
+        report.statements,
+        report.batches,
+        report.bytes,
+        report.elapsed_ms
+    );
+    Ok(report)
+}
+
+// Like `BufRead::read_line`, but reports invalid UTF-8 as an error naming the problem.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    let read = reader.read_until(b'\n', &mut bytes)?;
+    let text = String::from_utf8(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid UTF-8: {}", e)))?;
+    line.push_str(&text);
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(script: &str) -> Vec<Statement> {
+        let mut splitter = StatementSplitter::default();
+        let mut out = Vec::new();
+        for line in script.split_inclusive('\n') {
+            splitter.push_line(line, &mut out);
+        }
+        assert!(!splitter.is_unterminated());
+        out.extend(splitter.finish());
+        out
+    }
+
+    fn sqls(script: &str) -> Vec<String> {
+        split(script).into_iter().map(|s| s.sql).collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_outside_literals() {
+        assert_eq!(
+            sqls("DROP DATABASE IF EXISTS isuumo;\nCREATE DATABASE isuumo;\n"),
+            vec!["DROP DATABASE IF EXISTS isuumo", "CREATE DATABASE isuumo"]
+        );
+        assert_eq!(
+            sqls("INSERT INTO t VALUES ('a;b', \"c;d\", `e;f`);"),
+            vec!["INSERT INTO t VALUES ('a;b', \"c;d\", `e;f`)"]
+        );
+    }
+
+    #[test]
+    fn handles_escaped_and_doubled_quotes() {
+        assert_eq!(
+            sqls("INSERT INTO t VALUES ('it\\'s;', 'a''b;c');SELECT 1;"),
+            vec!["INSERT INTO t VALUES ('it\\'s;', 'a''b;c')", "SELECT 1"]
+        );
+    }
+
+    #[test]
+    fn drops_line_comments_and_keeps_block_comments() {
+        assert_eq!(
+            sqls("-- header; not a statement\n# also a comment;\n/*!40101 SET NAMES utf8 */;\nSELECT 1 /* a;b */;\n"),
+            vec!["/*!40101 SET NAMES utf8 */", "SELECT 1 /* a;b */"]
+        );
+    }
+
+    #[test]
+    fn statements_span_lines_and_report_their_start_line() {
+        let statements =
+            split("\n\nCREATE TABLE t\n(\n  id INT\n);\nINSERT INTO t VALUES\n(1),\n(2)");
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].line, 3);
+        assert_eq!(statements[0].sql, "CREATE TABLE t\n(\n  id INT\n)");
+        assert_eq!(statements[1].line, 7);
+        assert_eq!(statements[1].sql, "INSERT INTO t VALUES\n(1),\n(2)");
+    }
+
+    #[test]
+    fn multi_line_strings_keep_their_newlines() {
+        assert_eq!(
+            sqls("INSERT INTO t VALUES ('a\n-- not a comment;\nb');"),
+            vec!["INSERT INTO t VALUES ('a\n-- not a comment;\nb')"]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_strings() {
+        let mut splitter = StatementSplitter::default();
+        let mut out = Vec::new();
+        splitter.push_line("INSERT INTO t VALUES ('oops);\n", &mut out);
+        assert!(out.is_empty());
+        assert!(splitter.is_unterminated());
+    }
+}